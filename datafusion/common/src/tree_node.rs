@@ -17,15 +17,59 @@
 
 //! [`TreeNode`] for visiting and rewriting expression and plan trees
 
+use std::cell::{Cell, OnceCell};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::ops::ControlFlow;
 use std::sync::Arc;
 
-use crate::Result;
+use crate::error::_internal_err;
+use crate::{DataFusionError, Result};
 
-/// These macros are used to determine continuation during transforming traversals.
+/// Determines continuation during combined (top-down and bottom-up)
+/// transforming traversals, like [`TreeNode::transform_down_up`].
+///
+/// The macro expects three arguments:
+/// - `$F_DOWN`: a `Result<Transformed<N>>` expression, the result of the
+///   top-down transformation of the current node,
+/// - `$F_CHILD`: a `FnMut(N) -> Result<Transformed<N>>` that recursively
+///   transforms a child of the node,
+/// - `$F_UP`: a `FnOnce(N) -> Result<Transformed<N>>` that applies the
+///   bottom-up transformation on the node.
+///
+/// The children are transformed only if `$F_DOWN` returned
+/// [`TreeNodeRecursion::Continue`], and `$F_UP` is applied only if the recursion
+/// continues after the children, see [`TreeNodeRecursion`] for the details. The
+/// macro uses `?` on the result of `$F_DOWN` and the children so it must be used
+/// in a function returning [`Result`].
+///
+/// # Example
+/// ```
+/// # use datafusion_common::handle_transform_recursion;
+/// # use datafusion_common::tree_node::{Transformed, TreeNode};
+/// # use datafusion_common::Result;
+/// /// Same as `TreeNode::transform_down_up`, but `f_up` also receives the
+/// /// depth of the node.
+/// fn transform_down_up_with_depth<N: TreeNode>(
+///     node: N,
+///     depth: usize,
+///     f_down: &mut impl FnMut(N) -> Result<Transformed<N>>,
+///     f_up: &mut impl FnMut(N, usize) -> Result<Transformed<N>>,
+/// ) -> Result<Transformed<N>> {
+///     handle_transform_recursion!(
+///         f_down(node),
+///         |c| transform_down_up_with_depth(c, depth + 1, f_down, f_up),
+///         |n| f_up(n, depth)
+///     )
+/// }
+/// ```
+#[macro_export]
 macro_rules! handle_transform_recursion {
     ($F_DOWN:expr, $F_CHILD:expr, $F_UP:expr) => {{
         $F_DOWN?
-            .transform_children(|n| n.map_children($F_CHILD))?
+            .transform_children(|n| {
+                $crate::tree_node::TreeNode::map_children(n, $F_CHILD)
+            })?
             .transform_parent($F_UP)
     }};
 }
@@ -133,6 +177,26 @@ pub trait TreeNode: Sized {
             .visit_parent(|| visitor.f_up(self))
     }
 
+    /// Visits the tree with two independent [`TreeNodeVisitor`]s in a single
+    /// traversal, see [`TreeNodeVisitor::zip`].
+    ///
+    /// On each node `v1.f_down` and `v2.f_down` are called before, and `v1.f_up`
+    /// and `v2.f_up` are called after visiting the node's children. The returned
+    /// [`TreeNodeRecursion`]s are combined by taking the more restrictive one, so
+    /// for example a `Jump` returned by either `f_down` prunes the subtree for
+    /// both visitors and a `Stop` returned by either of them stops the traversal.
+    fn visit_both<
+        'n,
+        V1: TreeNodeVisitor<'n, Node = Self>,
+        V2: TreeNodeVisitor<'n, Node = Self>,
+    >(
+        &'n self,
+        v1: &mut V1,
+        v2: &mut V2,
+    ) -> Result<TreeNodeRecursion> {
+        self.visit(&mut BorrowedVisitor(v1).zip(BorrowedVisitor(v2)))
+    }
+
     /// Rewrite the tree node with a [`TreeNodeRewriter`], performing a
     /// depth-first walk of the node and its children.
     ///
@@ -176,11 +240,29 @@ pub trait TreeNode: Sized {
         self,
         rewriter: &mut R,
     ) -> Result<Transformed<Self>> {
+        if !rewriter.should_visit(&self) {
+            return self.map_children(|c| c.rewrite(rewriter));
+        }
         handle_transform_recursion!(rewriter.f_down(self), |c| c.rewrite(rewriter), |n| {
             rewriter.f_up(n)
         })
     }
 
+    /// Same as [`Self::rewrite`] but with a [`TreeNodeContextRewriter`], whose
+    /// `f_down` produces a context that is passed to the matching `f_up` call on
+    /// the same node.
+    ///
+    /// If `f_down` returns [`TreeNodeRecursion::Stop`] then `f_up` is not called
+    /// and the context is dropped.
+    fn rewrite_with_context<R: TreeNodeContextRewriter<Node = Self>>(
+        self,
+        rewriter: &mut R,
+    ) -> Result<Transformed<Self>> {
+        let (node, context) = rewriter.f_down(self)?;
+        node.transform_children(|n| n.map_children(|c| c.rewrite_with_context(rewriter)))?
+            .transform_parent(|n| rewriter.f_up(n, context))
+    }
+
     /// Applies `f` to the node then each of its children, recursively (a
     /// top-down, pre-order traversal).
     ///
@@ -204,6 +286,64 @@ pub trait TreeNode: Sized {
         apply_impl(self, &mut f)
     }
 
+    /// Applies `f` to each of the node's children and then to the node itself,
+    /// recursively (a bottom-up, post-order traversal).
+    ///
+    /// The return [`TreeNodeRecursion`] controls the recursion the same way as
+    /// in [`Self::transform_up`]: `Jump` bypasses calling `f` till the next leaf
+    /// node and `Stop` stops the recursion.
+    ///
+    /// # See Also
+    /// * [`Self::transform_up`] for the equivalent transformation API.
+    /// * [`Self::visit`] for both top-down and bottom up traversal.
+    fn apply_up<'n, F: FnMut(&'n Self) -> Result<TreeNodeRecursion>>(
+        &'n self,
+        mut f: F,
+    ) -> Result<TreeNodeRecursion> {
+        fn apply_up_impl<
+            'n,
+            N: TreeNode,
+            F: FnMut(&'n N) -> Result<TreeNodeRecursion>,
+        >(
+            node: &'n N,
+            f: &mut F,
+        ) -> Result<TreeNodeRecursion> {
+            node.apply_children(|c| apply_up_impl(c, f))?
+                .visit_parent(|| f(node))
+        }
+
+        apply_up_impl(self, &mut f)
+    }
+
+    /// Same as [`Self::apply`] but uses an explicit stack instead of recursion.
+    ///
+    /// The visit order and the handling of the returned [`TreeNodeRecursion`]
+    /// values are the same as in [`Self::apply`], but the traversal depth is
+    /// bounded by heap memory rather than the call stack. Use this method on
+    /// very deep trees that would overflow the stack with [`Self::apply`].
+    fn apply_iterative<'n, F: FnMut(&'n Self) -> Result<TreeNodeRecursion>>(
+        &'n self,
+        mut f: F,
+    ) -> Result<TreeNodeRecursion> {
+        let mut stack = vec![self];
+        while let Some(node) = stack.pop() {
+            match f(node)? {
+                TreeNodeRecursion::Continue => {
+                    let len = stack.len();
+                    node.apply_children(|c| {
+                        stack.push(c);
+                        Ok(TreeNodeRecursion::Continue)
+                    })?;
+                    // children need to be visited from left to right
+                    stack[len..].reverse();
+                }
+                TreeNodeRecursion::Jump => {}
+                TreeNodeRecursion::Stop => return Ok(TreeNodeRecursion::Stop),
+            }
+        }
+        Ok(TreeNodeRecursion::Continue)
+    }
+
     /// Recursively rewrite the node's children and then the node using `f`
     /// (a bottom-up post-order traversal).
     ///
@@ -220,6 +360,13 @@ pub trait TreeNode: Sized {
     ///
     /// `f` is applied to the node first, and then its children.
     ///
+    /// Note that `f` is also applied to the children of the node that `f`
+    /// returns, so if `f` wraps the node into a new one then `f` is applied to
+    /// the original node again, which can loop forever. To accept a replacement
+    /// without descending into it, return the replacement with
+    /// [`TreeNodeRecursion::Jump`], e.g. `Transformed::new(node, true, TreeNodeRecursion::Jump)`.
+    /// This is the recommended way to write idempotent top-down rules.
+    ///
     /// # See Also
     /// * [`Self::transform_up`] for a bottom-up (post-order) traversal.
     /// * [Self::transform_down_up] for a combined traversal with closures
@@ -247,6 +394,48 @@ pub trait TreeNode: Sized {
         self.transform_down(f)
     }
 
+    /// Extracts the subtrees whose roots match `pred`, in a top-down (pre-order)
+    /// traversal.
+    ///
+    /// Each extracted subtree is replaced with the node returned by `placeholder`
+    /// for the subtree's root, and the traversal doesn't descend into it (nor the
+    /// placeholder). Returns the tree with the placeholders and the extracted
+    /// subtrees in pre-order.
+    fn extract_subtrees<P: Fn(&Self) -> bool, F: Fn(&Self) -> Self>(
+        self,
+        pred: &P,
+        placeholder: &F,
+    ) -> Result<(Transformed<Self>, Vec<Self>)> {
+        let mut subtrees = vec![];
+        let transformed = self.transform_down(|n| {
+            Ok(if pred(&n) {
+                let p = placeholder(&n);
+                subtrees.push(n);
+                Transformed::new(p, true, TreeNodeRecursion::Jump)
+            } else {
+                Transformed::no(n)
+            })
+        })?;
+        Ok((transformed, subtrees))
+    }
+
+    /// Same as [`Self::transform_down`] but `f` also receives an immutable
+    /// reference to a shared context `ctx`.
+    ///
+    /// This is useful when the rewrite needs read-only access to a large
+    /// shared analysis result (e.g. a statistics catalog, possibly held in an
+    /// `Rc` or `Arc`) without capturing it in the closure.
+    fn transform_down_mut_with_ctx<
+        C: ?Sized,
+        F: FnMut(Self, &C) -> Result<Transformed<Self>>,
+    >(
+        self,
+        ctx: &C,
+        f: &mut F,
+    ) -> Result<Transformed<Self>> {
+        self.transform_down(|n| f(n, ctx))
+    }
+
     /// Recursively rewrite the node using `f` in a bottom-up (post-order)
     /// fashion.
     ///
@@ -280,6 +469,288 @@ pub trait TreeNode: Sized {
         self.transform_up(f)
     }
 
+    /// Same as [`Self::transform_up`] but instead of stopping at the first
+    /// error, keeps transforming the rest of the tree and returns all errors.
+    ///
+    /// On failure `f` hands the node back along with the error, the node is
+    /// left unchanged and the error is collected. This is useful for
+    /// best-effort rewrite passes that should try every node and report all
+    /// failures.
+    ///
+    /// Returns an error only if rebuilding a node from its transformed children
+    /// fails.
+    fn transform_up_collect_errors<
+        F: FnMut(Self) -> Result<Transformed<Self>, (Self, DataFusionError)>,
+    >(
+        self,
+        f: &mut F,
+    ) -> Result<(Transformed<Self>, Vec<DataFusionError>)> {
+        fn transform_up_collect_errors_impl<
+            N: TreeNode,
+            F: FnMut(N) -> Result<Transformed<N>, (N, DataFusionError)>,
+        >(
+            node: N,
+            f: &mut F,
+            errors: &mut Vec<DataFusionError>,
+        ) -> Result<Transformed<N>> {
+            node.map_children(|c| transform_up_collect_errors_impl(c, f, errors))?
+                .transform_parent(|n| {
+                    Ok(f(n).unwrap_or_else(|(n, e)| {
+                        errors.push(e);
+                        Transformed::no(n)
+                    }))
+                })
+        }
+
+        let mut errors = vec![];
+        let transformed = transform_up_collect_errors_impl(self, f, &mut errors)?;
+        Ok((transformed, errors))
+    }
+
+    /// Same as [`Self::transform_up`] but the number of times `f` is applied
+    /// to nodes of a given kind is limited.
+    ///
+    /// `key` returns the kind of a node and `budgets` contains the maximum
+    /// number of `f` applications for each kind. Once the budget of a kind is
+    /// exhausted, further nodes of that kind are left unchanged. The number of
+    /// applications on kinds without a budget is not limited.
+    ///
+    /// This is useful to bound rewrites that are cheap on most nodes but
+    /// expensive on a few kinds.
+    fn transform_up_kind_budget<
+        K: Eq + Hash,
+        KF: Fn(&Self) -> K,
+        F: FnMut(Self) -> Result<Transformed<Self>>,
+    >(
+        self,
+        mut budgets: HashMap<K, usize>,
+        key: KF,
+        f: &mut F,
+    ) -> Result<Transformed<Self>> {
+        self.transform_up(|n| match budgets.get_mut(&key(&n)) {
+            Some(0) => Ok(Transformed::no(n)),
+            Some(budget) => {
+                *budget -= 1;
+                f(n)
+            }
+            None => f(n),
+        })
+    }
+
+    /// Same as [`Self::transform_up`] but also returns the minimal set of subtree
+    /// roots that contain all changes made by `f`.
+    ///
+    /// Each root is identified by its index path from this node, e.g. `[]` is
+    /// this node and `[1, 0]` is the first child of the second child of this node.
+    /// A node is reported if `f` transformed it and `f` did not transform any of
+    /// its ancestors. Ancestors that are changed only because their children were
+    /// transformed are not reported. Roots are returned in post-order.
+    fn transform_up_with_changed_roots<F: FnMut(Self) -> Result<Transformed<Self>>>(
+        self,
+        mut f: F,
+    ) -> Result<(Transformed<Self>, Vec<Vec<usize>>)> {
+        fn transform_up_with_changed_roots_impl<
+            N: TreeNode,
+            F: FnMut(N) -> Result<Transformed<N>>,
+        >(
+            node: N,
+            f: &mut F,
+            path: &mut Vec<usize>,
+            roots: &mut Vec<Vec<usize>>,
+        ) -> Result<Transformed<N>> {
+            let mut child_index = 0;
+            node.map_children(|c| {
+                path.push(child_index);
+                child_index += 1;
+                let t = transform_up_with_changed_roots_impl(c, f, path, roots);
+                path.pop();
+                t
+            })?
+            .transform_parent(|n| {
+                let t = f(n)?;
+                if t.transformed {
+                    roots.retain(|root| !root.starts_with(path));
+                    roots.push(path.clone());
+                }
+                Ok(t)
+            })
+        }
+
+        let mut roots = vec![];
+        let transformed =
+            transform_up_with_changed_roots_impl(self, &mut f, &mut vec![], &mut roots)?;
+        Ok((transformed, roots))
+    }
+
+    /// Repeatedly applies [`Self::transform_up`] with `f` until a pass no longer
+    /// transforms the tree, and returns the final tree and the number of passes
+    /// performed, including the last one that made no change.
+    ///
+    /// Returns an error if the tree is still changing after `max_iterations`
+    /// passes.
+    fn transform_to_fixpoint<F: Fn(Self) -> Result<Transformed<Self>>>(
+        self,
+        f: &F,
+        max_iterations: usize,
+    ) -> Result<(Self, usize)> {
+        let mut node = self;
+        for iteration in 1..=max_iterations {
+            let Transformed {
+                data, transformed, ..
+            } = node.transform_up(f)?;
+            if !transformed {
+                return Ok((data, iteration));
+            }
+            node = data;
+        }
+        _internal_err!("Fixpoint not reached after {max_iterations} iterations")
+    }
+
+    /// Same as [`Self::transform_up`] but also returns how many nodes of each kind
+    /// were reconstructed, keyed by the `label` of the reconstructed nodes.
+    ///
+    /// A node is reconstructed if `f` transformed it or any of its descendants,
+    /// as the node needs to be rebuilt with its new children in the latter case.
+    /// An identity transformation reconstructs no nodes.
+    fn transform_up_with_rebuild_counts<
+        K: Eq + Hash,
+        L: Fn(&Self) -> K,
+        F: FnMut(Self) -> Result<Transformed<Self>>,
+    >(
+        self,
+        label: &L,
+        mut f: F,
+    ) -> Result<(Transformed<Self>, HashMap<K, usize>)> {
+        fn transform_up_with_rebuild_counts_impl<
+            N: TreeNode,
+            K: Eq + Hash,
+            L: Fn(&N) -> K,
+            F: FnMut(N) -> Result<Transformed<N>>,
+        >(
+            node: N,
+            label: &L,
+            f: &mut F,
+            counts: &mut HashMap<K, usize>,
+        ) -> Result<Transformed<N>> {
+            node.map_children(|c| {
+                transform_up_with_rebuild_counts_impl(c, label, f, counts)
+            })?
+            .transform_parent(f)
+            .inspect(|t| {
+                if t.transformed {
+                    *counts.entry(label(&t.data)).or_default() += 1;
+                }
+            })
+        }
+
+        let mut counts = HashMap::new();
+        let transformed =
+            transform_up_with_rebuild_counts_impl(self, label, &mut f, &mut counts)?;
+        Ok((transformed, counts))
+    }
+
+    /// Same as [`Self::transform_up`] but siblings that are equal are transformed
+    /// only once.
+    ///
+    /// Before transforming the children of a node, each child is compared to its
+    /// previous siblings. If a child is equal to one of them, the result of the
+    /// first equal sibling is reused instead of transforming the child again.
+    /// Results are cloned only for such repeated children. Equal subtrees that
+    /// are not siblings are transformed separately.
+    fn transform_up_shared<F: FnMut(Self) -> Result<Transformed<Self>>>(
+        self,
+        f: &mut F,
+    ) -> Result<Transformed<Self>>
+    where
+        Self: Clone + Eq,
+    {
+        fn transform_up_shared_impl<
+            N: TreeNode + Clone + Eq,
+            F: FnMut(N) -> Result<Transformed<N>>,
+        >(
+            node: N,
+            f: &mut F,
+        ) -> Result<Transformed<N>> {
+            // The index of the first equal previous sibling of each child
+            let mut children = vec![];
+            let mut first_equal = vec![];
+            node.apply_children(|c| {
+                first_equal.push(children.iter().position(|&s| s == c));
+                children.push(c);
+                Ok(TreeNodeRecursion::Continue)
+            })
+            .expect("traversal is infallible");
+            let mut repeated = vec![false; children.len()];
+            first_equal
+                .iter()
+                .flatten()
+                .for_each(|&i| repeated[i] = true);
+
+            let mut results = HashMap::<usize, Transformed<N>>::new();
+            let mut index = 0;
+            node.map_children(|c| {
+                let i = index;
+                index += 1;
+                if let Some(t) = first_equal[i].and_then(|j| results.get(&j)) {
+                    return Ok(Transformed::new(t.data.clone(), t.transformed, t.tnr));
+                }
+                let t = transform_up_shared_impl(c, f)?;
+                if repeated[i] {
+                    results.insert(
+                        i,
+                        Transformed::new(t.data.clone(), t.transformed, t.tnr),
+                    );
+                }
+                Ok(t)
+            })?
+            .transform_parent(f)
+        }
+
+        transform_up_shared_impl(self, f)
+    }
+
+    /// Same as [`Self::transform_down`] but also returns the number of nodes for
+    /// which `f` returned [`Transformed::yes`].
+    ///
+    /// The count is accumulated during the traversal, no second pass is needed.
+    fn transform_down_with_count<F: Fn(Self) -> Result<Transformed<Self>>>(
+        self,
+        f: &F,
+    ) -> Result<(Self, usize)> {
+        let mut count = 0;
+        let node = self
+            .transform_down(|n| {
+                f(n).inspect(|t| {
+                    if t.transformed {
+                        count += 1;
+                    }
+                })
+            })?
+            .data;
+        Ok((node, count))
+    }
+
+    /// Same as [`Self::transform_up`] but also returns the number of nodes for
+    /// which `f` returned [`Transformed::yes`].
+    ///
+    /// The count is accumulated during the traversal, no second pass is needed.
+    fn transform_up_with_count<F: Fn(Self) -> Result<Transformed<Self>>>(
+        self,
+        f: &F,
+    ) -> Result<(Self, usize)> {
+        let mut count = 0;
+        let node = self
+            .transform_up(|n| {
+                f(n).inspect(|t| {
+                    if t.transformed {
+                        count += 1;
+                    }
+                })
+            })?
+            .data;
+        Ok((node, count))
+    }
+
     /// Transforms the node using `f_down` while traversing the tree top-down
     /// (pre-order), and using `f_up` while traversing the tree bottom-up
     /// (post-order).
@@ -402,6 +873,150 @@ pub trait TreeNode: Sized {
         transform_down_up_impl(self, &mut f_down, &mut f_up)
     }
 
+    /// Same as [`Self::transform_down_up`] but if `f_down` or `f_up` returns an
+    /// error, the error is wrapped in a [`DataFusionError::Context`] that
+    /// contains the depth and the path of child indices from the root of the
+    /// node where the error occurred.
+    fn transform_down_up_with_context<
+        FD: FnMut(Self) -> Result<Transformed<Self>>,
+        FU: FnMut(Self) -> Result<Transformed<Self>>,
+    >(
+        self,
+        mut f_down: FD,
+        mut f_up: FU,
+    ) -> Result<Transformed<Self>> {
+        fn add_context(e: DataFusionError, path: &[usize]) -> DataFusionError {
+            e.context(format!(
+                "Error at depth {} with child path {path:?}",
+                path.len()
+            ))
+        }
+
+        fn transform_down_up_with_context_impl<
+            N: TreeNode,
+            FD: FnMut(N) -> Result<Transformed<N>>,
+            FU: FnMut(N) -> Result<Transformed<N>>,
+        >(
+            node: N,
+            f_down: &mut FD,
+            f_up: &mut FU,
+            path: &mut Vec<usize>,
+        ) -> Result<Transformed<N>> {
+            f_down(node)
+                .map_err(|e| add_context(e, path))?
+                .transform_children(|n| {
+                    n.map_children_indexed(|i, c| {
+                        path.push(i);
+                        let result =
+                            transform_down_up_with_context_impl(c, f_down, f_up, path);
+                        path.pop();
+                        result
+                    })
+                })?
+                .transform_parent(|n| f_up(n).map_err(|e| add_context(e, path)))
+        }
+
+        transform_down_up_with_context_impl(self, &mut f_down, &mut f_up, &mut vec![])
+    }
+
+    /// Same as [`Self::transform_down_up`] but `f_down` and `f_up` also receive
+    /// a flag indicating whether the node they are applied on is a leaf node.
+    ///
+    /// `f_down` receives the leaf-ness of the node before it is transformed and
+    /// `f_up` receives the leaf-ness of the node after its children have been
+    /// transformed.
+    fn transform_down_up_with_leaf<
+        FD: FnMut(Self, bool) -> Result<Transformed<Self>>,
+        FU: FnMut(Self, bool) -> Result<Transformed<Self>>,
+    >(
+        self,
+        mut f_down: FD,
+        mut f_up: FU,
+    ) -> Result<Transformed<Self>> {
+        fn is_leaf<N: TreeNode>(node: &N) -> bool {
+            node.apply_children(|_| Ok(TreeNodeRecursion::Stop))
+                .expect("traversal is infallible")
+                == TreeNodeRecursion::Continue
+        }
+
+        fn transform_down_up_with_leaf_impl<
+            N: TreeNode,
+            FD: FnMut(N, bool) -> Result<Transformed<N>>,
+            FU: FnMut(N, bool) -> Result<Transformed<N>>,
+        >(
+            node: N,
+            f_down: &mut FD,
+            f_up: &mut FU,
+        ) -> Result<Transformed<N>> {
+            let leaf = is_leaf(&node);
+            handle_transform_recursion!(
+                f_down(node, leaf),
+                |c| transform_down_up_with_leaf_impl(c, f_down, f_up),
+                |n| {
+                    let leaf = is_leaf(&n);
+                    f_up(n, leaf)
+                }
+            )
+        }
+
+        transform_down_up_with_leaf_impl(self, &mut f_down, &mut f_up)
+    }
+
+    /// Transforms the tree using `f_down` and `f_up`, similarly to
+    /// [`Self::transform_down_up`], but also threads payloads through the
+    /// traversal.
+    ///
+    /// `f_down` receives a node with the down-payload of the node and returns the
+    /// transformed node with one down-payload per child of the transformed node.
+    /// `f_up` receives a node with the up-payloads of its children and returns the
+    /// transformed node with the up-payload of the node.
+    ///
+    /// The returned up-payload is the one produced by `f_up` on this node.
+    ///
+    /// # Ordering
+    /// * `f_down` is called on a node before any of its descendants,
+    /// * children are traversed in the order they are visited by
+    ///   [`Self::map_children`],
+    /// * `f_up` is called on a node after `f_up` is called on all its children,
+    ///   and the up-payloads are passed in the same order as the children.
+    ///
+    /// # Errors
+    /// Returns an error if `f_down` doesn't return exactly one down-payload for
+    /// each child.
+    fn transform_with_payload<FD, PD, FU, PU>(
+        self,
+        f_down: &mut FD,
+        payload: PD,
+        f_up: &mut FU,
+    ) -> Result<(Self, PU)>
+    where
+        FD: FnMut(Self, PD) -> Result<(Self, Vec<PD>)>,
+        FU: FnMut(Self, Vec<PU>) -> Result<(Self, PU)>,
+    {
+        let (node, child_payloads) = f_down(self, payload)?;
+        let child_payload_count = child_payloads.len();
+        let mut child_payloads = child_payloads.into_iter();
+        let mut child_up_payloads = Vec::with_capacity(child_payload_count);
+        let node = node
+            .map_children(|c| {
+                let Some(payload) = child_payloads.next() else {
+                    return _internal_err!("Not enough payloads for the children");
+                };
+                let (c, up_payload) = c.transform_with_payload(f_down, payload, f_up)?;
+                child_up_payloads.push(up_payload);
+                Ok(Transformed::yes(c))
+            })?
+            .data;
+        if child_up_payloads.len() != child_payload_count {
+            return _internal_err!(
+                "Expected {} children but got {}",
+                child_payload_count,
+                child_up_payloads.len()
+            );
+        }
+        f_up(node, child_up_payloads)
+    }
+
     /// Returns true if `f` returns true for any node in the tree.
     ///
     /// Stops recursion as soon as a matching node is found
@@ -418,38 +1033,764 @@ pub trait TreeNode: Sized {
         .map(|_| found)
     }
 
-    /// Low-level API used to implement other APIs.
+    /// Returns the number of nodes in the tree for which `f` returns true.
     ///
-    /// If you want to implement the [`TreeNode`] trait for your own type, you
-    /// should implement this method and [`Self::map_children`].
+    /// Unlike [`Self::exists`], all nodes of the tree are visited.
+    fn count<F: FnMut(&Self) -> bool>(&self, mut f: F) -> usize {
+        let mut count = 0;
+        self.apply(|n| {
+            if f(n) {
+                count += 1;
+            }
+            Ok(TreeNodeRecursion::Continue)
+        })
+        .expect("traversal is infallible");
+        count
+    }
+
+    /// Threads a summary value through a top-down (pre-order) traversal of the
+    /// tree and returns its final value.
     ///
-    /// Users should use one of the higher level APIs described on [`Self`].
+    /// `f` receives the current summary and the visited node, and returns the
+    /// new summary and the [`TreeNodeRecursion`] that controls the traversal, so
+    /// it can end the traversal with [`TreeNodeRecursion::Stop`] once enough
+    /// information is gathered.
+    fn apply_until<S, F: FnMut(S, &Self) -> Result<(S, TreeNodeRecursion)>>(
+        &self,
+        init: S,
+        f: &mut F,
+    ) -> Result<S> {
+        let mut summary = Some(init);
+        self.apply(|n| {
+            let (new_summary, tnr) =
+                f(summary.take().expect("summary is always set"), n)?;
+            summary = Some(new_summary);
+            Ok(tnr)
+        })?;
+        Ok(summary.expect("summary is always set"))
+    }
+
+    /// Folds the nodes of the tree into an accumulator in a top-down (pre-order)
+    /// traversal.
     ///
-    /// Description: Apply `f` to inspect node's children (but not the node
-    /// itself).
-    fn apply_children<'n, F: FnMut(&'n Self) -> Result<TreeNodeRecursion>>(
-        &'n self,
-        f: F,
-    ) -> Result<TreeNodeRecursion>;
+    /// `f` receives the accumulator and the visited node, and returns the new
+    /// accumulator. Returning [`ControlFlow::Break`] ends the traversal, and
+    /// returning an error ends the traversal with the error.
+    fn try_fold<B, F: FnMut(B, &Self) -> Result<ControlFlow<B, B>>>(
+        &self,
+        init: B,
+        mut f: F,
+    ) -> Result<B> {
+        let mut acc = Some(init);
+        self.apply(|n| {
+            let acc_value = acc.take().expect("accumulator is always set");
+            Ok(match f(acc_value, n)? {
+                ControlFlow::Continue(acc_value) => {
+                    acc = Some(acc_value);
+                    TreeNodeRecursion::Continue
+                }
+                ControlFlow::Break(acc_value) => {
+                    acc = Some(acc_value);
+                    TreeNodeRecursion::Stop
+                }
+            })
+        })?;
+        Ok(acc.expect("accumulator is always set"))
+    }
 
-    /// Low-level API used to implement other APIs.
+    /// Returns the first non-`None` result of `f` in a top-down (pre-order)
+    /// traversal of the tree.
     ///
-    /// If you want to implement the [`TreeNode`] trait for your own type, you
-    /// should implement this method and [`Self::apply_children`].
+    /// The traversal stops at the first node `f` returns `Some` for.
+    fn find_map<T, F: FnMut(&Self) -> Option<T>>(&self, mut f: F) -> Option<T> {
+        let mut found = None;
+        self.apply(|n| {
+            Ok(match f(n) {
+                Some(value) => {
+                    found = Some(value);
+                    TreeNodeRecursion::Stop
+                }
+                None => TreeNodeRecursion::Continue,
+            })
+        })
+        .expect("traversal is infallible");
+        found
+    }
+
+    /// Returns true if this tree and `other` have the same structure and all the
+    /// corresponding nodes are equal according to `node_eq`.
     ///
-    /// Users should use one of the higher level APIs described on [`Self`].
+    /// The trees are compared in lockstep, in top-down (pre-order) fashion, and
+    /// the comparison returns false as soon as two corresponding nodes have
+    /// different number of children or `node_eq` returns false for them.
+    fn structurally_eq<F: Fn(&Self, &Self) -> bool>(
+        &self,
+        other: &Self,
+        node_eq: F,
+    ) -> bool {
+        fn children<N: TreeNode>(node: &N) -> Vec<&N> {
+            let mut children = vec![];
+            node.apply_children(|c| {
+                children.push(c);
+                Ok(TreeNodeRecursion::Continue)
+            })
+            .expect("traversal is infallible");
+            children
+        }
+
+        fn structurally_eq_impl<N: TreeNode, F: Fn(&N, &N) -> bool>(
+            node: &N,
+            other: &N,
+            node_eq: &F,
+        ) -> bool {
+            if !node_eq(node, other) {
+                return false;
+            }
+            let (children, other_children) = (children(node), children(other));
+            children.len() == other_children.len()
+                && children
+                    .into_iter()
+                    .zip(other_children)
+                    .all(|(c, o)| structurally_eq_impl(c, o, node_eq))
+        }
+
+        structurally_eq_impl(self, other, &node_eq)
+    }
+
+    /// Visits this tree and `other` in lockstep, calling `f` on the pairs of
+    /// corresponding nodes in top-down (pre-order) fashion.
     ///
-    /// Description: Apply `f` to rewrite the node's children (but not the node itself).
-    fn map_children<F: FnMut(Self) -> Result<Transformed<Self>>>(
-        self,
-        f: F,
-    ) -> Result<Transformed<Self>>;
-}
+    /// The returned [`TreeNodeRecursion`] of `f` is honored the same way as in
+    /// [`Self::apply`]. Returns an error if the trees diverge, i.e. a pair of
+    /// visited nodes have a different number of children.
+    fn zip_apply<F: FnMut(&Self, &Self) -> Result<TreeNodeRecursion>>(
+        &self,
+        other: &Self,
+        mut f: F,
+    ) -> Result<TreeNodeRecursion> {
+        fn children<N: TreeNode>(node: &N) -> Vec<&N> {
+            let mut children = vec![];
+            node.apply_children(|c| {
+                children.push(c);
+                Ok(TreeNodeRecursion::Continue)
+            })
+            .expect("traversal is infallible");
+            children
+        }
 
-/// A [Visitor](https://en.wikipedia.org/wiki/Visitor_pattern) for recursively
-/// inspecting [`TreeNode`]s via [`TreeNode::visit`].
-///
-/// See [`TreeNode`] for more details on available APIs
+        fn zip_apply_impl<N: TreeNode, F: FnMut(&N, &N) -> Result<TreeNodeRecursion>>(
+            node: &N,
+            other: &N,
+            f: &mut F,
+        ) -> Result<TreeNodeRecursion> {
+            f(node, other)?.visit_children(|| {
+                let (children, other_children) = (children(node), children(other));
+                if children.len() != other_children.len() {
+                    return _internal_err!(
+                        "Trees diverge: nodes with {} and {} children",
+                        children.len(),
+                        other_children.len()
+                    );
+                }
+                children
+                    .into_iter()
+                    .zip(other_children)
+                    .apply_until_stop(|(c, o)| zip_apply_impl(c, o, f))
+            })
+        }
+
+        zip_apply_impl(self, other, &mut f)
+    }
+
+    /// Returns the number of children of this node.
+    ///
+    /// The children are counted via [`Self::apply_children`], so no allocation
+    /// is needed if the implementation of `apply_children` doesn't allocate.
+    fn child_count(&self) -> usize {
+        let mut count = 0;
+        self.apply_children(|_| {
+            count += 1;
+            Ok(TreeNodeRecursion::Continue)
+        })
+        .expect("traversal is infallible");
+        count
+    }
+
+    /// Returns the maximum number of children any node of the tree has.
+    ///
+    /// Leaf-only trees have a fan-out of 0.
+    fn max_fanout(&self) -> usize {
+        let mut max_fanout = 0;
+        self.apply(|n| {
+            max_fanout = max_fanout.max(n.child_count());
+            Ok(TreeNodeRecursion::Continue)
+        })
+        .expect("traversal is infallible");
+        max_fanout
+    }
+
+    /// Same as [`Self::apply`] but visits only the nodes at most `max_depth`
+    /// levels below this node.
+    ///
+    /// The node itself is at depth 0. The nodes at `max_depth` are visited, but
+    /// their children are skipped as if `f` returned [`TreeNodeRecursion::Jump`].
+    fn apply_to_depth<F: FnMut(&Self) -> Result<TreeNodeRecursion>>(
+        &self,
+        max_depth: usize,
+        mut f: F,
+    ) -> Result<TreeNodeRecursion> {
+        fn apply_to_depth_impl<N: TreeNode, F: FnMut(&N) -> Result<TreeNodeRecursion>>(
+            node: &N,
+            depth: usize,
+            max_depth: usize,
+            f: &mut F,
+        ) -> Result<TreeNodeRecursion> {
+            f(node)?.visit_children(|| {
+                if depth < max_depth {
+                    node.apply_children(|c| {
+                        apply_to_depth_impl(c, depth + 1, max_depth, f)
+                    })
+                } else {
+                    Ok(TreeNodeRecursion::Continue)
+                }
+            })
+        }
+
+        apply_to_depth_impl(self, 0, max_depth, &mut f)
+    }
+
+    /// Same as [`Self::apply`] but calls `f` only on the leaf nodes, i.e. on the
+    /// nodes without children.
+    fn apply_leaves<F: FnMut(&Self) -> Result<TreeNodeRecursion>>(
+        &self,
+        mut f: F,
+    ) -> Result<TreeNodeRecursion> {
+        self.apply(|n| {
+            if n.child_count() == 0 {
+                f(n)
+            } else {
+                Ok(TreeNodeRecursion::Continue)
+            }
+        })
+    }
+
+    /// Returns all nodes exactly `depth` levels below this node, in pre-order.
+    ///
+    /// The node itself is at depth 0. Subtrees below the requested depth are
+    /// not visited.
+    fn nodes_at_depth(&self, depth: usize) -> Vec<&Self> {
+        fn nodes_at_depth_impl<'n, N: TreeNode>(
+            node: &'n N,
+            current_depth: usize,
+            depth: usize,
+            nodes: &mut Vec<&'n N>,
+        ) -> Result<TreeNodeRecursion> {
+            if current_depth == depth {
+                nodes.push(node);
+                return Ok(TreeNodeRecursion::Jump);
+            }
+            node.apply_children(|c| {
+                nodes_at_depth_impl(c, current_depth + 1, depth, nodes)
+            })
+        }
+
+        let mut nodes = vec![];
+        nodes_at_depth_impl(self, 0, depth, &mut nodes).expect("traversal is infallible");
+        nodes
+    }
+
+    /// Returns all nodes of the tree in top-down (pre-order) fashion.
+    fn collect_pre_order(&self) -> Vec<&Self> {
+        let mut nodes = vec![];
+        self.apply(|n| {
+            nodes.push(n);
+            Ok(TreeNodeRecursion::Continue)
+        })
+        .expect("traversal is infallible");
+        nodes
+    }
+
+    /// Returns all nodes of the tree in bottom-up (post-order) fashion.
+    fn collect_post_order(&self) -> Vec<&Self> {
+        let mut nodes = vec![];
+        self.apply_up(|n| {
+            nodes.push(n);
+            Ok(TreeNodeRecursion::Continue)
+        })
+        .expect("traversal is infallible");
+        nodes
+    }
+
+    /// Returns the subtrees that occur at least twice in the tree, each distinct
+    /// subtree once, in bottom-up (post-order) fashion of their second
+    /// occurrence.
+    ///
+    /// As the descendants of a repeated subtree are repeated too, they are also
+    /// returned, before the subtree itself.
+    ///
+    /// `node_key` should return the data of a node apart from its children. Each
+    /// subtree gets an id from the key of its root and the ids of its children,
+    /// so every node is hashed only once and the whole detection is linear in
+    /// the size of the tree.
+    fn duplicate_subtrees<K: Hash + Eq, F: FnMut(&Self) -> K>(
+        &self,
+        node_key: F,
+    ) -> Vec<&Self> {
+        struct HashConsingVisitor<'n, N, K, F> {
+            node_key: F,
+            ids: HashMap<(K, Vec<usize>), usize>,
+            counts: Vec<usize>,
+            /// The ids of the already visited children of the nodes on the
+            /// current path
+            child_ids: Vec<Vec<usize>>,
+            duplicates: Vec<&'n N>,
+        }
+
+        impl<'n, N: TreeNode, K: Hash + Eq, F: FnMut(&N) -> K> TreeNodeVisitor<'n>
+            for HashConsingVisitor<'n, N, K, F>
+        {
+            type Node = N;
+
+            fn f_down(&mut self, _node: &'n N) -> Result<TreeNodeRecursion> {
+                self.child_ids.push(vec![]);
+                Ok(TreeNodeRecursion::Continue)
+            }
+
+            fn f_up(&mut self, node: &'n N) -> Result<TreeNodeRecursion> {
+                let child_ids = self.child_ids.pop().unwrap_or_default();
+                let next_id = self.ids.len();
+                let id = *self
+                    .ids
+                    .entry(((self.node_key)(node), child_ids))
+                    .or_insert(next_id);
+                if id == self.counts.len() {
+                    self.counts.push(0);
+                }
+                self.counts[id] += 1;
+                if self.counts[id] == 2 {
+                    self.duplicates.push(node);
+                }
+                if let Some(parent_child_ids) = self.child_ids.last_mut() {
+                    parent_child_ids.push(id);
+                }
+                Ok(TreeNodeRecursion::Continue)
+            }
+        }
+
+        let mut visitor = HashConsingVisitor {
+            node_key,
+            ids: HashMap::new(),
+            counts: vec![],
+            child_ids: vec![],
+            duplicates: vec![],
+        };
+        self.visit(&mut visitor).expect("traversal is infallible");
+        visitor.duplicates
+    }
+
+    /// Renders the tree in pre-order, one node per line, formatted by
+    /// `node_fmt` and indented by two spaces per level.
+    ///
+    /// Every line, including the last one, ends with a newline.
+    fn format_indented<F: Fn(&Self) -> String>(&self, node_fmt: F) -> String {
+        struct IndentingVisitor<N, F> {
+            node_fmt: F,
+            depth: usize,
+            output: String,
+            _node: std::marker::PhantomData<N>,
+        }
+
+        impl<'n, N: TreeNode, F: Fn(&N) -> String> TreeNodeVisitor<'n>
+            for IndentingVisitor<N, F>
+        {
+            type Node = N;
+
+            fn f_down(&mut self, node: &'n N) -> Result<TreeNodeRecursion> {
+                for _ in 0..self.depth {
+                    self.output.push_str("  ");
+                }
+                self.output.push_str(&(self.node_fmt)(node));
+                self.output.push('\n');
+                self.depth += 1;
+                Ok(TreeNodeRecursion::Continue)
+            }
+
+            fn f_up(&mut self, _node: &'n N) -> Result<TreeNodeRecursion> {
+                self.depth -= 1;
+                Ok(TreeNodeRecursion::Continue)
+            }
+        }
+
+        let mut visitor = IndentingVisitor {
+            node_fmt,
+            depth: 0,
+            output: String::new(),
+            _node: std::marker::PhantomData,
+        };
+        self.visit(&mut visitor).expect("traversal is infallible");
+        visitor.output
+    }
+
+    /// Returns all paths from this node to the leaf nodes of the tree, in
+    /// pre-order.
+    ///
+    /// Each path starts with this node and ends with a leaf node.
+    fn root_to_leaf_paths(&self) -> Vec<Vec<&Self>> {
+        fn root_to_leaf_paths_impl<'n, N: TreeNode>(
+            node: &'n N,
+            path: &mut Vec<&'n N>,
+            paths: &mut Vec<Vec<&'n N>>,
+        ) -> Result<TreeNodeRecursion> {
+            path.push(node);
+            let mut is_leaf = true;
+            node.apply_children(|c| {
+                is_leaf = false;
+                root_to_leaf_paths_impl(c, path, paths)
+            })?;
+            if is_leaf {
+                paths.push(path.clone());
+            }
+            path.pop();
+            Ok(TreeNodeRecursion::Continue)
+        }
+
+        let mut paths = vec![];
+        root_to_leaf_paths_impl(self, &mut vec![], &mut paths)
+            .expect("traversal is infallible");
+        paths
+    }
+
+    /// Returns the [`SubtreeStats`] of every node of the tree, keyed by the index
+    /// path of the node from this node.
+    ///
+    /// The stats are computed in a single post-order traversal. `[]` is this
+    /// node and `[1, 0]` is the first child of the second child of this node.
+    fn subtree_stats(&self) -> HashMap<Vec<usize>, SubtreeStats> {
+        fn subtree_stats_impl<N: TreeNode>(
+            node: &N,
+            path: &mut Vec<usize>,
+            stats: &mut HashMap<Vec<usize>, SubtreeStats>,
+        ) -> SubtreeStats {
+            let mut node_stats = SubtreeStats {
+                size: 1,
+                height: 1,
+                leaves: 0,
+            };
+            let mut child_index = 0;
+            node.apply_children(|c| {
+                path.push(child_index);
+                child_index += 1;
+                let child_stats = subtree_stats_impl(c, path, stats);
+                path.pop();
+                node_stats.size += child_stats.size;
+                node_stats.height = node_stats.height.max(child_stats.height + 1);
+                node_stats.leaves += child_stats.leaves;
+                Ok(TreeNodeRecursion::Continue)
+            })
+            .expect("traversal is infallible");
+            if child_index == 0 {
+                node_stats.leaves = 1;
+            }
+            stats.insert(path.clone(), node_stats);
+            node_stats
+        }
+
+        let mut stats = HashMap::new();
+        subtree_stats_impl(self, &mut vec![], &mut stats);
+        stats
+    }
+
+    /// Matches this node against `template` and returns the subtrees bound to the
+    /// holes of the template, or `None` if the node doesn't match.
+    ///
+    /// A [`Template::Hole`] matches any subtree, but if the same hole name occurs
+    /// multiple times in the template then all occurrences must match equal
+    /// subtrees. A [`Template::Node`] matches a node for which `node_eq` returns
+    /// true with the template's node and whose children match the template's
+    /// children. `node_eq` should compare the nodes apart from their children.
+    fn match_template<F: FnMut(&Self, &Self) -> bool>(
+        &self,
+        template: &Template<Self>,
+        mut node_eq: F,
+    ) -> Option<Bindings<Self>>
+    where
+        Self: Clone + PartialEq,
+    {
+        fn match_template_impl<
+            N: TreeNode + Clone + PartialEq,
+            F: FnMut(&N, &N) -> bool,
+        >(
+            node: &N,
+            template: &Template<N>,
+            node_eq: &mut F,
+            bindings: &mut Bindings<N>,
+        ) -> bool {
+            match template {
+                Template::Hole(name) => match bindings.get(name) {
+                    Some(bound) => bound == node,
+                    None => {
+                        bindings.insert(name.clone(), node.clone());
+                        true
+                    }
+                },
+                Template::Node {
+                    node: template_node,
+                    children: template_children,
+                } => {
+                    if !node_eq(node, template_node) {
+                        return false;
+                    }
+                    let mut children = vec![];
+                    node.apply_children(|c| {
+                        children.push(c);
+                        Ok(TreeNodeRecursion::Continue)
+                    })
+                    .expect("traversal is infallible");
+                    children.len() == template_children.len()
+                        && children
+                            .into_iter()
+                            .zip(template_children)
+                            .all(|(c, t)| match_template_impl(c, t, node_eq, bindings))
+                }
+            }
+        }
+
+        let mut bindings = Bindings::new();
+        match_template_impl(self, template, &mut node_eq, &mut bindings)
+            .then_some(bindings)
+    }
+
+    /// Builds a new tree from `template` by replacing its holes with the subtrees
+    /// bound to them in `bindings`.
+    ///
+    /// Returns an error if a hole is not bound, or if the node of a
+    /// [`Template::Node`] doesn't have the same number of children as the
+    /// template.
+    fn instantiate(template: Template<Self>, bindings: &Bindings<Self>) -> Result<Self>
+    where
+        Self: Clone,
+    {
+        match template {
+            Template::Hole(name) => match bindings.get(&name) {
+                Some(bound) => Ok(bound.clone()),
+                None => _internal_err!("Template hole {name} is not bound"),
+            },
+            Template::Node { node, children } => {
+                let children = children
+                    .into_iter()
+                    .map(|c| Self::instantiate(c, bindings))
+                    .collect::<Result<Vec<_>>>()?;
+                with_new_children(node, children)
+            }
+        }
+    }
+
+    /// Returns a [`TreePatch`] that transforms this tree into `optimized` when
+    /// applied with [`Self::apply_patch`].
+    ///
+    /// The patch contains the minimal changed subtrees of `optimized`: a subtree
+    /// is recorded if `node_eq` returns false for its root and the corresponding
+    /// node of this tree, or if the two nodes have a different number of
+    /// children. `node_eq` should compare the nodes apart from their children.
+    fn make_patch<F: FnMut(&Self, &Self) -> bool>(
+        &self,
+        optimized: &Self,
+        mut node_eq: F,
+    ) -> Result<TreePatch<Self>>
+    where
+        Self: Clone,
+    {
+        fn children<N: TreeNode>(node: &N) -> Vec<&N> {
+            let mut children = vec![];
+            node.apply_children(|c| {
+                children.push(c);
+                Ok(TreeNodeRecursion::Continue)
+            })
+            .expect("traversal is infallible");
+            children
+        }
+
+        fn make_patch_impl<N: TreeNode + Clone, F: FnMut(&N, &N) -> bool>(
+            node: &N,
+            optimized: &N,
+            node_eq: &mut F,
+            path: &mut Vec<usize>,
+            entries: &mut Vec<(Vec<usize>, N)>,
+        ) {
+            let (node_children, optimized_children) =
+                (children(node), children(optimized));
+            if node_children.len() == optimized_children.len() && node_eq(node, optimized)
+            {
+                for (i, (c, o)) in node_children
+                    .into_iter()
+                    .zip(optimized_children)
+                    .enumerate()
+                {
+                    path.push(i);
+                    make_patch_impl(c, o, node_eq, path, entries);
+                    path.pop();
+                }
+            } else {
+                entries.push((path.clone(), optimized.clone()));
+            }
+        }
+
+        let mut entries = vec![];
+        make_patch_impl(self, optimized, &mut node_eq, &mut vec![], &mut entries);
+        Ok(TreePatch { entries })
+    }
+
+    /// Applies a [`TreePatch`] created by [`Self::make_patch`], replacing the
+    /// subtrees at the index paths of the patch.
+    ///
+    /// Returns an error if a path of the patch doesn't exist in this tree.
+    fn apply_patch(self, patch: &TreePatch<Self>) -> Result<Self>
+    where
+        Self: Clone,
+    {
+        fn replace_at<N: TreeNode + Clone>(
+            node: N,
+            path: &[usize],
+            replacement: &N,
+        ) -> Result<N> {
+            let Some((&index, rest)) = path.split_first() else {
+                return Ok(replacement.clone());
+            };
+            let mut child_index = 0;
+            let mut found = false;
+            let node = node
+                .map_children(|c| {
+                    let t = if child_index == index {
+                        found = true;
+                        Transformed::yes(replace_at(c, rest, replacement)?)
+                    } else {
+                        Transformed::no(c)
+                    };
+                    child_index += 1;
+                    Ok(t)
+                })?
+                .data;
+            if !found {
+                return _internal_err!("Invalid patch path, child {index} doesn't exist");
+            }
+            Ok(node)
+        }
+
+        patch
+            .entries
+            .iter()
+            .try_fold(self, |node, (path, replacement)| {
+                replace_at(node, path, replacement)
+            })
+    }
+
+    /// Returns a [`ControlledIter`] that iterates the nodes of the tree in
+    /// top-down (pre-order) fashion.
+    ///
+    /// Unlike [`Self::apply`], the traversal is driven by the caller, who can
+    /// decide to skip the subtree of the last returned node with
+    /// [`ControlledIter::prune`] or end the iteration with
+    /// [`ControlledIter::stop`].
+    fn controlled_iter(&self) -> ControlledIter<'_, Self> {
+        ControlledIter {
+            stack: vec![self],
+            last: None,
+        }
+    }
+
+    /// Same as [`Self::map_children`] but `f` also receives the index of the
+    /// child among the children of this node.
+    fn map_children_indexed<F: FnMut(usize, Self) -> Result<Transformed<Self>>>(
+        self,
+        mut f: F,
+    ) -> Result<Transformed<Self>> {
+        let mut index = 0;
+        self.map_children(|c| {
+            let t = f(index, c);
+            index += 1;
+            t
+        })
+    }
+
+    /// Low-level API used to implement other APIs.
+    ///
+    /// If you want to implement the [`TreeNode`] trait for your own type, you
+    /// should implement this method and [`Self::map_children`]. Types that can
+    /// cheaply iterate over their children can implement it with
+    /// [`TreeNodeIterator::apply_until_stop`] over [`Self::children_iter`].
+    ///
+    /// Users should use one of the higher level APIs described on [`Self`].
+    ///
+    /// Description: Apply `f` to inspect node's children (but not the node
+    /// itself).
+    fn apply_children<'n, F: FnMut(&'n Self) -> Result<TreeNodeRecursion>>(
+        &'n self,
+        f: F,
+    ) -> Result<TreeNodeRecursion>;
+
+    /// Low-level API used to implement other APIs.
+    ///
+    /// The default implementation collects the children visited by
+    /// [`Self::apply_children`]. Types that can cheaply iterate over their
+    /// children should override it and implement [`Self::apply_children`] on top
+    /// of it.
+    ///
+    /// Description: Returns an iterator over the node's children (but not the
+    /// node itself).
+    fn children_iter(&self) -> impl Iterator<Item = &Self> {
+        let mut children = vec![];
+        self.apply_children(|c| {
+            children.push(c);
+            Ok(TreeNodeRecursion::Continue)
+        })
+        .expect("traversal is infallible");
+        children.into_iter()
+    }
+
+    /// Same as [`Self::apply_children`] but visits the children in reverse
+    /// order, from the last child to the first one.
+    fn apply_children_rev<'n, F: FnMut(&'n Self) -> Result<TreeNodeRecursion>>(
+        &'n self,
+        f: F,
+    ) -> Result<TreeNodeRecursion> {
+        let mut children = vec![];
+        self.apply_children(|c| {
+            children.push(c);
+            Ok(TreeNodeRecursion::Continue)
+        })?;
+        children.into_iter().rev().apply_until_stop(f)
+    }
+
+    /// Low-level API used to implement other APIs.
+    ///
+    /// If you want to implement the [`TreeNode`] trait for your own type, you
+    /// should implement this method and [`Self::apply_children`].
+    ///
+    /// Users should use one of the higher level APIs described on [`Self`].
+    ///
+    /// Description: Apply `f` to rewrite the node's children (but not the node itself).
+    fn map_children<F: FnMut(Self) -> Result<Transformed<Self>>>(
+        self,
+        f: F,
+    ) -> Result<Transformed<Self>>;
+}
+
+/// Aggregate statistics of a subtree, see [`TreeNode::subtree_stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubtreeStats {
+    /// The number of nodes in the subtree
+    pub size: usize,
+    /// The number of nodes on the longest path from the root of the subtree to
+    /// a leaf
+    pub height: usize,
+    /// The number of leaf nodes in the subtree
+    pub leaves: usize,
+}
+
+/// A [Visitor](https://en.wikipedia.org/wiki/Visitor_pattern) for recursively
+/// inspecting [`TreeNode`]s via [`TreeNode::visit`].
+///
+/// See [`TreeNode`] for more details on available APIs
 ///
 /// When passed to [`TreeNode::visit`], [`TreeNodeVisitor::f_down`] and
 /// [`TreeNodeVisitor::f_up`] are invoked recursively on the tree.
@@ -481,6 +1822,159 @@ pub trait TreeNodeVisitor<'n>: Sized {
     fn f_up(&mut self, _node: &'n Self::Node) -> Result<TreeNodeRecursion> {
         Ok(TreeNodeRecursion::Continue)
     }
+
+    /// Combines this visitor with `other` so that both visitors run in a single
+    /// traversal, see [`ZipVisitor`].
+    fn zip<V2: TreeNodeVisitor<'n, Node = Self::Node>>(
+        self,
+        other: V2,
+    ) -> ZipVisitor<Self, V2> {
+        ZipVisitor {
+            first: self,
+            second: other,
+        }
+    }
+}
+
+/// A [`TreeNodeVisitor`] that runs two visitors in a single traversal, created
+/// by [`TreeNodeVisitor::zip`].
+///
+/// Both visitors' `f_down` and `f_up` are called on each visited node, and the
+/// traversal continues with the more restrictive of their returned
+/// [`TreeNodeRecursion`]s, where `Stop` wins over `Jump` and `Jump` wins over
+/// `Continue`. This means that the nodes skipped by one visitor are skipped by
+/// the other as well.
+pub struct ZipVisitor<V1, V2> {
+    first: V1,
+    second: V2,
+}
+
+impl<V1, V2> ZipVisitor<V1, V2> {
+    /// Consumes the combinator and returns the two visitors.
+    pub fn into_inner(self) -> (V1, V2) {
+        (self.first, self.second)
+    }
+}
+
+impl<'n, V1: TreeNodeVisitor<'n>, V2: TreeNodeVisitor<'n, Node = V1::Node>>
+    TreeNodeVisitor<'n> for ZipVisitor<V1, V2>
+{
+    type Node = V1::Node;
+
+    fn f_down(&mut self, node: &'n Self::Node) -> Result<TreeNodeRecursion> {
+        let tnr = self.first.f_down(node)?;
+        Ok(tnr.most_restrictive(self.second.f_down(node)?))
+    }
+
+    fn f_up(&mut self, node: &'n Self::Node) -> Result<TreeNodeRecursion> {
+        let tnr = self.first.f_up(node)?;
+        Ok(tnr.most_restrictive(self.second.f_up(node)?))
+    }
+}
+
+/// A [`TreeNodeVisitor`] that forwards to a borrowed visitor, used to zip
+/// borrowed visitors in [`TreeNode::visit_both`].
+struct BorrowedVisitor<'a, V>(&'a mut V);
+
+impl<'n, V: TreeNodeVisitor<'n>> TreeNodeVisitor<'n> for BorrowedVisitor<'_, V> {
+    type Node = V::Node;
+
+    fn f_down(&mut self, node: &'n Self::Node) -> Result<TreeNodeRecursion> {
+        self.0.f_down(node)
+    }
+
+    fn f_up(&mut self, node: &'n Self::Node) -> Result<TreeNodeRecursion> {
+        self.0.f_up(node)
+    }
+}
+
+/// A [`TreeNodeVisitor`] adapter that delegates to an inner visitor and keeps
+/// track of the depth of the currently visited node.
+///
+/// The root node is at depth 0. The depth is tracked correctly even if the
+/// inner visitor returns [`TreeNodeRecursion::Jump`] from `f_up` and so some
+/// `f_up` calls are bypassed.
+///
+/// # Example
+/// ```
+/// # use datafusion_common::tree_node::{DepthTrackingVisitor, TreeNode, TreeNodeRecursion, TreeNodeVisitor};
+/// # use datafusion_common::Result;
+/// # fn visit<'n, N: TreeNode, V: TreeNodeVisitor<'n, Node = N>>(tree: &'n N, visitor: V) -> Result<()> {
+/// let mut visitor = DepthTrackingVisitor::new(visitor);
+/// tree.visit(&mut visitor)?;
+/// // the depth of the node the traversal stopped at
+/// let depth = visitor.depth();
+/// # Ok(())
+/// # }
+/// ```
+pub struct DepthTrackingVisitor<'n, V: TreeNodeVisitor<'n>> {
+    inner: V,
+    /// The currently visited node and its ancestors
+    path: Vec<&'n V::Node>,
+}
+
+impl<'n, V: TreeNodeVisitor<'n>> DepthTrackingVisitor<'n, V> {
+    /// Creates a new depth tracking adapter around `inner`.
+    pub fn new(inner: V) -> Self {
+        Self {
+            inner,
+            path: vec![],
+        }
+    }
+
+    /// Returns the depth of the currently visited node.
+    pub fn depth(&self) -> usize {
+        self.path.len().saturating_sub(1)
+    }
+
+    /// Returns a reference to the inner visitor.
+    pub fn inner(&self) -> &V {
+        &self.inner
+    }
+
+    /// Consumes the adapter and returns the inner visitor.
+    pub fn into_inner(self) -> V {
+        self.inner
+    }
+}
+
+impl<'n, V: TreeNodeVisitor<'n>> TreeNodeVisitor<'n> for DepthTrackingVisitor<'n, V> {
+    type Node = V::Node;
+
+    fn f_down(&mut self, node: &'n Self::Node) -> Result<TreeNodeRecursion> {
+        // Drop the nodes whose `f_up` was bypassed due to a `Jump`
+        while let Some(parent) = self.path.last() {
+            let is_parent = parent
+                .apply_children(|c| {
+                    Ok(if std::ptr::eq(c, node) {
+                        TreeNodeRecursion::Stop
+                    } else {
+                        TreeNodeRecursion::Continue
+                    })
+                })
+                .expect("traversal is infallible")
+                == TreeNodeRecursion::Stop;
+            if is_parent {
+                break;
+            }
+            self.path.pop();
+        }
+        self.path.push(node);
+        self.inner.f_down(node)
+    }
+
+    fn f_up(&mut self, node: &'n Self::Node) -> Result<TreeNodeRecursion> {
+        // Drop the descendants whose `f_up` was bypassed due to a `Jump`
+        while let Some(last) = self.path.last() {
+            if std::ptr::eq(*last, node) {
+                break;
+            }
+            self.path.pop();
+        }
+        let tnr = self.inner.f_up(node)?;
+        self.path.pop();
+        Ok(tnr)
+    }
 }
 
 /// A [Visitor](https://en.wikipedia.org/wiki/Visitor_pattern) for recursively
@@ -521,6 +2015,485 @@ pub trait TreeNodeRewriter: Sized {
     fn f_up(&mut self, node: Self::Node) -> Result<Transformed<Self::Node>> {
         Ok(Transformed::no(node))
     }
+
+    /// Invoked before `f_down` to decide whether the rewriter is interested in
+    /// `node`. If this returns false then neither `f_down` nor `f_up` is called
+    /// on `node`, but its children are still rewritten. Default implementation
+    /// visits all nodes.
+    fn should_visit(&self, _node: &Self::Node) -> bool {
+        true
+    }
+
+    /// Same as `f_up` but can replace the node with any number of sibling
+    /// nodes, including zero. Invoked instead of `f_up` by
+    /// [`ConcreteTreeNode::rewrite_flat`] on all nodes except the root, as only
+    /// the children of a node can be expanded. Default implementation calls
+    /// `f_up`.
+    fn f_up_expand(&mut self, node: Self::Node) -> Result<Transformed<Vec<Self::Node>>> {
+        Ok(self.f_up(node)?.update_data(|node| vec![node]))
+    }
+}
+
+/// A [`TreeNodeRewriter`] adapter that delegates to an inner rewriter, but
+/// limits the number of changes the rewrite makes.
+///
+/// Each [`Transformed::yes`] result of the inner rewriter's `f_down` or `f_up`
+/// uses up one unit of the budget, and once the budget is exhausted the
+/// recursion stops with [`TreeNodeRecursion::Stop`], returning the partially
+/// rewritten tree.
+pub struct BoundedRewriter<R: TreeNodeRewriter> {
+    inner: R,
+    /// The number of changes the rewrite can still make
+    budget: usize,
+}
+
+impl<R: TreeNodeRewriter> BoundedRewriter<R> {
+    /// Creates a new adapter around `inner` that allows at most `budget`
+    /// changes.
+    pub fn new(inner: R, budget: usize) -> Self {
+        Self { inner, budget }
+    }
+
+    /// Returns the number of changes the rewrite can still make.
+    pub fn remaining(&self) -> usize {
+        self.budget
+    }
+
+    /// Returns a reference to the inner rewriter.
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    /// Consumes the adapter and returns the inner rewriter.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn limit(&mut self, mut t: Transformed<R::Node>) -> Transformed<R::Node> {
+        if t.transformed {
+            self.budget -= 1;
+            if self.budget == 0 {
+                t.tnr = TreeNodeRecursion::Stop;
+            }
+        }
+        t
+    }
+}
+
+impl<R: TreeNodeRewriter> TreeNodeRewriter for BoundedRewriter<R> {
+    type Node = R::Node;
+
+    fn f_down(&mut self, node: Self::Node) -> Result<Transformed<Self::Node>> {
+        if self.budget == 0 {
+            return Ok(Transformed::new(node, false, TreeNodeRecursion::Stop));
+        }
+        let t = self.inner.f_down(node)?;
+        Ok(self.limit(t))
+    }
+
+    fn f_up(&mut self, node: Self::Node) -> Result<Transformed<Self::Node>> {
+        if self.budget == 0 {
+            return Ok(Transformed::new(node, false, TreeNodeRecursion::Stop));
+        }
+        let t = self.inner.f_up(node)?;
+        Ok(self.limit(t))
+    }
+
+    fn should_visit(&self, node: &Self::Node) -> bool {
+        self.inner.should_visit(node)
+    }
+}
+
+/// A [`TreeNodeRewriter`] adapter that delegates to an inner rewriter and
+/// records where the inner rewriter transformed the tree.
+///
+/// A location is recorded as the path of child indices from the root to the
+/// transformed node, so the root is at the empty path. The path is recorded
+/// each time the inner rewriter's `f_down` or `f_up` returns a transformed node.
+pub struct RewriteRecorder<R: TreeNodeRewriter> {
+    inner: R,
+    /// The path of the currently rewritten node
+    path: Vec<usize>,
+    /// The number of visited and total children of the currently rewritten node
+    /// and its ancestors, and whether the inner rewriter visits them
+    children: Vec<(usize, usize, bool)>,
+    changed_paths: Vec<Vec<usize>>,
+}
+
+impl<R: TreeNodeRewriter> RewriteRecorder<R> {
+    /// Creates a new recording adapter around `inner`.
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            path: vec![],
+            children: vec![],
+            changed_paths: vec![],
+        }
+    }
+
+    /// Returns the paths of the transformed nodes in the order the inner
+    /// rewriter transformed them.
+    pub fn changed_paths(&self) -> &[Vec<usize>] {
+        &self.changed_paths
+    }
+
+    /// Returns a reference to the inner rewriter.
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    /// Consumes the adapter and returns the inner rewriter.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn record(&mut self, t: &Transformed<R::Node>) {
+        if t.transformed {
+            self.changed_paths.push(self.path.clone());
+        }
+    }
+}
+
+impl<R: TreeNodeRewriter> TreeNodeRewriter for RewriteRecorder<R> {
+    type Node = R::Node;
+
+    fn f_down(&mut self, node: Self::Node) -> Result<Transformed<Self::Node>> {
+        // Drop the nodes whose `f_up` was bypassed due to a `Jump`
+        while let Some((visited, count, _)) = self.children.last() {
+            if visited < count {
+                break;
+            }
+            self.children.pop();
+            self.path.pop();
+        }
+        if let Some((visited, _, _)) = self.children.last_mut() {
+            self.path.push(*visited);
+            *visited += 1;
+        }
+        // The adapter visits all nodes to track the path, but calls the inner
+        // rewriter only on the nodes it is interested in
+        let should_visit = self.inner.should_visit(&node);
+        let t = if should_visit {
+            self.inner.f_down(node)?
+        } else {
+            Transformed::no(node)
+        };
+        self.record(&t);
+        self.children.push((0, t.data.child_count(), should_visit));
+        Ok(t)
+    }
+
+    fn f_up(&mut self, node: Self::Node) -> Result<Transformed<Self::Node>> {
+        let should_visit = self.children.last().is_some_and(|(_, _, v)| *v);
+        let t = if should_visit {
+            self.inner.f_up(node)?
+        } else {
+            Transformed::no(node)
+        };
+        self.record(&t);
+        self.children.pop();
+        self.path.pop();
+        Ok(t)
+    }
+}
+
+/// A [`TreeNodeRewriter`] like trait for rewriting [`TreeNode`]s via
+/// [`TreeNode::rewrite_with_context`], that can pass per-node context from
+/// `f_down` to the matching `f_up`.
+///
+/// This is useful when `f_up` needs some information that was computed in
+/// `f_down` on the same node (e.g. the column mapping established when entering
+/// a projection), without the rewriter maintaining a stack of such values.
+///
+/// See [`TreeNodeRecursion`] for more details on controlling the traversal.
+pub trait TreeNodeContextRewriter: Sized {
+    /// The node type which is rewritable.
+    type Node: TreeNode;
+
+    /// The per-node context that is passed from `f_down` to `f_up`.
+    type Context;
+
+    /// Invoked while traversing down the tree before any children are rewritten.
+    /// Returns the rewritten node and the context for the `f_up` call on the node.
+    fn f_down(
+        &mut self,
+        node: Self::Node,
+    ) -> Result<(Transformed<Self::Node>, Self::Context)>;
+
+    /// Invoked while traversing up the tree after all children have been
+    /// rewritten, with the context that `f_down` returned on the node.
+    /// Default implementation returns the node as is and continues recursion.
+    fn f_up(
+        &mut self,
+        node: Self::Node,
+        _context: Self::Context,
+    ) -> Result<Transformed<Self::Node>> {
+        Ok(Transformed::no(node))
+    }
+}
+
+/// An iterator over the nodes of a [`TreeNode`] tree in top-down (pre-order)
+/// fashion, whose traversal can be controlled while iterating.
+///
+/// See [`TreeNode::controlled_iter`] for more details.
+pub struct ControlledIter<'n, N: TreeNode> {
+    /// Nodes to be visited, the next node is on the top of the stack
+    stack: Vec<&'n N>,
+    /// The last returned node whose children are not pushed to `stack` yet
+    last: Option<&'n N>,
+}
+
+impl<'n, N: TreeNode> ControlledIter<'n, N> {
+    /// Skips the subtree of the last returned node, which is [`TreeNodeRecursion::Jump`]
+    /// in terms of [`TreeNode::apply`].
+    pub fn prune(&mut self) {
+        self.last = None;
+    }
+
+    /// Ends the iteration, which is [`TreeNodeRecursion::Stop`] in terms of
+    /// [`TreeNode::apply`].
+    pub fn stop(&mut self) {
+        self.last = None;
+        self.stack.clear();
+    }
+}
+
+impl<'n, N: TreeNode> Iterator for ControlledIter<'n, N> {
+    type Item = &'n N;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(last) = self.last.take() {
+            let len = self.stack.len();
+            last.apply_children(|c| {
+                self.stack.push(c);
+                Ok(TreeNodeRecursion::Continue)
+            })
+            .expect("traversal is infallible");
+            // children need to be visited from left to right
+            self.stack[len..].reverse();
+        }
+        self.last = self.stack.pop();
+        self.last
+    }
+}
+
+/// Applies a rule to the nodes of a [`TreeNode`] tree in bottom-up (post-order)
+/// fashion, one node at a time.
+///
+/// Unlike [`TreeNode::transform_up`], which transforms the whole tree in one
+/// pass, each [`StepwiseTransform::step`] call applies the rule to a single node,
+/// so the caller can inspect the tree between the rule applications. This is
+/// useful for debugging optimizer rules.
+///
+/// The next node is always located in the current state of the tree, so if the
+/// rule changes the number of nodes in the tree then the subsequent steps
+/// visit the nodes of the changed tree.
+pub struct StepwiseTransform<N: TreeNode, F: FnMut(N) -> Result<Transformed<N>>> {
+    /// The tree, `None` only if a previous step failed
+    tree: Option<N>,
+    rule: F,
+    /// The post-order index of the next node the rule is applied to
+    next: usize,
+}
+
+impl<N: TreeNode, F: FnMut(N) -> Result<Transformed<N>>> StepwiseTransform<N, F> {
+    /// Creates a new stepwise transformation that applies `rule` to `tree`.
+    pub fn new(tree: N, rule: F) -> Self {
+        Self {
+            tree: Some(tree),
+            rule,
+            next: 0,
+        }
+    }
+
+    /// Returns the current state of the tree, or `None` if a previous step
+    /// failed.
+    pub fn tree(&self) -> Option<&N> {
+        self.tree.as_ref()
+    }
+
+    /// Consumes the stepwise transformation and returns the tree, or `None` if
+    /// a previous step failed.
+    pub fn into_tree(self) -> Option<N> {
+        self.tree
+    }
+
+    /// Applies the rule to the next node in post-order.
+    ///
+    /// Returns `None` if all nodes have been visited, otherwise returns whether
+    /// the rule transformed the node. The [`TreeNodeRecursion`] returned by the
+    /// rule is ignored.
+    pub fn step(&mut self) -> Result<Option<Transformed<()>>> {
+        let Some(tree) = self.tree.take() else {
+            return _internal_err!("StepwiseTransform failed in a previous step");
+        };
+        let mut index = 0;
+        let mut applied = None;
+        let tree = tree.transform_up(|node| {
+            if index == self.next {
+                let t = (self.rule)(node)?;
+                applied = Some(t.transformed);
+                Ok(Transformed::new(
+                    t.data,
+                    t.transformed,
+                    TreeNodeRecursion::Stop,
+                ))
+            } else {
+                index += 1;
+                Ok(Transformed::no(node))
+            }
+        })?;
+        self.tree = Some(tree.data);
+        Ok(applied.map(|transformed| {
+            self.next += 1;
+            Transformed::new((), transformed, TreeNodeRecursion::Continue)
+        }))
+    }
+}
+
+/// A tree pattern with named holes, see [`TreeNode::match_template`] and
+/// [`TreeNode::instantiate`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Template<N> {
+    /// Matches any subtree and binds it to the name
+    Hole(String),
+    /// Matches a node that is equal to `node` apart from its children, as decided
+    /// by the `node_eq` closure of [`TreeNode::match_template`], and whose
+    /// children match `children`
+    ///
+    /// The children of `node` are ignored, but `node` must have the same number
+    /// of children as `children`.
+    Node { node: N, children: Vec<Template<N>> },
+}
+
+impl<N> Template<N> {
+    /// Creates a new hole with the given name.
+    pub fn hole(name: impl Into<String>) -> Self {
+        Template::Hole(name.into())
+    }
+
+    /// Creates a new node template.
+    pub fn node(node: N, children: Vec<Template<N>>) -> Self {
+        Template::Node { node, children }
+    }
+}
+
+/// The subtrees bound to the holes of a [`Template`], keyed by hole name.
+pub type Bindings<N> = HashMap<String, N>;
+
+/// The differences between two trees, see [`TreeNode::make_patch`] and
+/// [`TreeNode::apply_patch`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreePatch<N> {
+    /// The index paths and the replacement subtrees, in pre-order
+    entries: Vec<(Vec<usize>, N)>,
+}
+
+impl<N> TreePatch<N> {
+    /// Returns the index paths and the replacement subtrees of the patch.
+    ///
+    /// `[]` is the root and `[1, 0]` is the first child of the second child of
+    /// the root.
+    pub fn entries(&self) -> &[(Vec<usize>, N)] {
+        &self.entries
+    }
+
+    /// Returns true if the patch doesn't change anything.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Replaces the children of `node` with `children`, in order.
+fn with_new_children<N: TreeNode>(node: N, children: Vec<N>) -> Result<N> {
+    let mut children = children.into_iter();
+    let node = node
+        .map_children(|_| match children.next() {
+            Some(c) => Ok(Transformed::yes(c)),
+            None => _internal_err!("Node has more children than the template"),
+        })?
+        .data;
+    if children.next().is_some() {
+        return _internal_err!("Node has fewer children than the template");
+    }
+    Ok(node)
+}
+
+/// A [`TreeNode`] whose children are built lazily by a closure.
+///
+/// The closure is called at most once, when the children are first accessed, and
+/// the children are cached afterwards. This is useful for trees where some
+/// subtrees are expensive to build, as the traversals build only the subtrees
+/// they visit.
+pub struct LazyNode<T> {
+    /// The payload of the node
+    pub data: T,
+    children: OnceCell<Vec<LazyNode<T>>>,
+    /// `None` once the children are built
+    init: Cell<Option<LazyChildren<T>>>,
+}
+
+type LazyChildren<T> = Box<dyn FnOnce() -> Vec<LazyNode<T>>>;
+
+impl<T> LazyNode<T> {
+    /// Creates a new node whose children are built by `children` on first access.
+    pub fn new(data: T, children: impl FnOnce() -> Vec<LazyNode<T>> + 'static) -> Self {
+        Self {
+            data,
+            children: OnceCell::new(),
+            init: Cell::new(Some(Box::new(children))),
+        }
+    }
+
+    /// Creates a new node with already built children.
+    pub fn new_materialized(data: T, children: Vec<LazyNode<T>>) -> Self {
+        Self {
+            data,
+            children: OnceCell::from(children),
+            init: Cell::new(None),
+        }
+    }
+
+    /// Returns true if the children of the node have been built.
+    pub fn is_materialized(&self) -> bool {
+        self.children.get().is_some()
+    }
+
+    /// Returns the children of the node, building them if needed.
+    pub fn children(&self) -> &[LazyNode<T>] {
+        self.children.get_or_init(|| {
+            let init = self.init.take().expect("children are not built yet");
+            init()
+        })
+    }
+}
+
+impl<T> TreeNode for LazyNode<T> {
+    fn apply_children<'n, F: FnMut(&'n Self) -> Result<TreeNodeRecursion>>(
+        &'n self,
+        f: F,
+    ) -> Result<TreeNodeRecursion> {
+        self.children().iter().apply_until_stop(f)
+    }
+
+    fn map_children<F: FnMut(Self) -> Result<Transformed<Self>>>(
+        self,
+        f: F,
+    ) -> Result<Transformed<Self>> {
+        let LazyNode {
+            data,
+            children,
+            init,
+        } = self;
+        let children = children.into_inner().unwrap_or_else(|| {
+            let init = init.into_inner().expect("children are not built yet");
+            init()
+        });
+        children
+            .into_iter()
+            .map_until_stop_and_collect(f)?
+            .map_data(|children| Ok(LazyNode::new_materialized(data, children)))
+    }
 }
 
 /// Controls how [`TreeNode`] recursions should proceed.
@@ -545,6 +2518,21 @@ pub enum TreeNodeRecursion {
 }
 
 impl TreeNodeRecursion {
+    /// Returns true if this is [`TreeNodeRecursion::Continue`].
+    pub fn is_continue(&self) -> bool {
+        matches!(self, TreeNodeRecursion::Continue)
+    }
+
+    /// Returns true if this is [`TreeNodeRecursion::Jump`].
+    pub fn is_jump(&self) -> bool {
+        matches!(self, TreeNodeRecursion::Jump)
+    }
+
+    /// Returns true if this is [`TreeNodeRecursion::Stop`].
+    pub fn is_stop(&self) -> bool {
+        matches!(self, TreeNodeRecursion::Stop)
+    }
+
     /// Continues visiting nodes with `f` depending on the current [`TreeNodeRecursion`]
     /// value and the fact that `f` is visiting the current node's children.
     pub fn visit_children<F: FnOnce() -> Result<TreeNodeRecursion>>(
@@ -581,6 +2569,32 @@ impl TreeNodeRecursion {
             TreeNodeRecursion::Jump | TreeNodeRecursion::Stop => Ok(self),
         }
     }
+
+    /// Returns `other` if `self` is [`TreeNodeRecursion::Continue`], otherwise
+    /// returns `self`.
+    ///
+    /// This is the eager counterpart of the `visit_*` methods, useful for
+    /// combining already computed [`TreeNodeRecursion`] values.
+    pub fn then(self, other: TreeNodeRecursion) -> TreeNodeRecursion {
+        match self {
+            TreeNodeRecursion::Continue => other,
+            TreeNodeRecursion::Jump | TreeNodeRecursion::Stop => self,
+        }
+    }
+
+    /// Returns the more restrictive of `self` and `other`, where `Stop` wins over
+    /// `Jump` and `Jump` wins over `Continue`.
+    fn most_restrictive(self, other: TreeNodeRecursion) -> TreeNodeRecursion {
+        match (self, other) {
+            (TreeNodeRecursion::Stop, _) | (_, TreeNodeRecursion::Stop) => {
+                TreeNodeRecursion::Stop
+            }
+            (TreeNodeRecursion::Jump, _) | (_, TreeNodeRecursion::Jump) => {
+                TreeNodeRecursion::Jump
+            }
+            _ => TreeNodeRecursion::Continue,
+        }
+    }
 }
 
 /// Result of tree walk / transformation APIs
@@ -696,6 +2710,21 @@ impl<T> Transformed<T> {
         Self::new(data, false, TreeNodeRecursion::Continue)
     }
 
+    /// Wrapper for transformed data with [`TreeNodeRecursion::Stop`] statement.
+    pub fn yes_and_stop(data: T) -> Self {
+        Self::new(data, true, TreeNodeRecursion::Stop)
+    }
+
+    /// Wrapper for transformed data with [`TreeNodeRecursion::Jump`] statement.
+    pub fn yes_and_jump(data: T) -> Self {
+        Self::new(data, true, TreeNodeRecursion::Jump)
+    }
+
+    /// Wrapper for unchanged data with [`TreeNodeRecursion::Stop`] statement.
+    pub fn no_and_stop(data: T) -> Self {
+        Self::new(data, false, TreeNodeRecursion::Stop)
+    }
+
     /// Applies an infallible `f` to the data of this [`Transformed`] object,
     /// without modifying the `transformed` flag.
     pub fn update_data<U, F: FnOnce(T) -> U>(self, f: F) -> Transformed<U> {
@@ -708,6 +2737,42 @@ impl<T> Transformed<T> {
         f(self.data).map(|data| Transformed::new(data, self.transformed, self.tnr))
     }
 
+    /// Returns the data of this [`Transformed`] object if the `transformed` flag
+    /// is set, otherwise returns `None`.
+    pub fn into_data_if_transformed(self) -> Option<T> {
+        self.transformed.then_some(self.data)
+    }
+
+    /// Replaces the [`TreeNodeRecursion`] of this [`Transformed`] object with
+    /// `tnr`, without modifying the data or the `transformed` flag.
+    pub fn with_tnr(self, tnr: TreeNodeRecursion) -> Transformed<T> {
+        Transformed::new(self.data, self.transformed, tnr)
+    }
+
+    /// Applies `f` to the [`TreeNodeRecursion`] of this [`Transformed`] object,
+    /// without modifying the data or the `transformed` flag.
+    pub fn map_tnr<F: FnOnce(TreeNodeRecursion) -> TreeNodeRecursion>(
+        self,
+        f: F,
+    ) -> Transformed<T> {
+        let tnr = f(self.tnr);
+        self.with_tnr(tnr)
+    }
+
+    /// Combines this [`Transformed`] object with `other` into a [`Transformed`]
+    /// tuple of their data.
+    ///
+    /// The result has the `transformed` flag set if either of the two has it
+    /// set, and the more restrictive of the two [`TreeNodeRecursion`] values,
+    /// where `Stop` wins over `Jump` and `Jump` wins over `Continue`.
+    pub fn zip<U>(self, other: Transformed<U>) -> Transformed<(T, U)> {
+        Transformed::new(
+            (self.data, other.data),
+            self.transformed || other.transformed,
+            self.tnr.most_restrictive(other.tnr),
+        )
+    }
+
     /// Applies a fallible transforming `f` to the data of this [`Transformed`]
     /// object.
     ///
@@ -723,6 +2788,36 @@ impl<T> Transformed<T> {
         })
     }
 
+    /// Applies a fallible transforming `f` to the data of this [`Transformed`]
+    /// object, regardless of the current [`TreeNodeRecursion`] value.
+    ///
+    /// The returned `Transformed` object has the `transformed` flag set if either
+    /// `self` or the return value of `f` have the `transformed` flag set, and the
+    /// more restrictive of the two [`TreeNodeRecursion`] values, where `Stop` wins
+    /// over `Jump` and `Jump` wins over `Continue`.
+    pub fn and_then<F: FnOnce(T) -> Result<Transformed<T>>>(
+        self,
+        f: F,
+    ) -> Result<Transformed<T>> {
+        f(self.data).map(|mut t| {
+            t.transformed |= self.transformed;
+            t.tnr = self.tnr.most_restrictive(t.tnr);
+            t
+        })
+    }
+
+    /// Returns the data if the `transformed` flag is set, otherwise returns an
+    /// internal error with the given `msg`.
+    ///
+    /// This is useful for rewrites that are expected to always make a change.
+    pub fn expect_transformed(self, msg: &str) -> Result<T> {
+        if self.transformed {
+            Ok(self.data)
+        } else {
+            _internal_err!("{msg}")
+        }
+    }
+
     /// Maps the [`Transformed`] object to the result of the given `f` depending on the
     /// current [`TreeNodeRecursion`] value and the fact that `f` is changing the current
     /// node's children.
@@ -780,6 +2875,37 @@ impl<T> Transformed<T> {
     }
 }
 
+impl<T> Transformed<Transformed<T>> {
+    /// Flattens a nested [`Transformed`] object, similarly to [`Option::flatten`].
+    ///
+    /// The returned `Transformed` object has the `transformed` flag set if either
+    /// the outer or the inner object have the `transformed` flag set, and the more
+    /// restrictive of the two [`TreeNodeRecursion`] values, where `Stop` wins over
+    /// `Jump` and `Jump` wins over `Continue`.
+    pub fn flatten(self) -> Transformed<T> {
+        Transformed::new(
+            self.data.data,
+            self.transformed || self.data.transformed,
+            self.tnr.most_restrictive(self.data.tnr),
+        )
+    }
+}
+
+impl<U, V> Transformed<(U, V)> {
+    /// Splits a [`Transformed`] tuple into two [`Transformed`] objects, the
+    /// reverse of [`Transformed::zip`].
+    ///
+    /// Both returned objects have the `transformed` flag and the
+    /// [`TreeNodeRecursion`] of `self`.
+    pub fn unzip(self) -> (Transformed<U>, Transformed<V>) {
+        let (u, v) = self.data;
+        (
+            Transformed::new(u, self.transformed, self.tnr),
+            Transformed::new(v, self.transformed, self.tnr),
+        )
+    }
+}
+
 /// Transformation helper to process a sequence of iterable tree nodes that are siblings.
 pub trait TreeNodeIterator: Iterator {
     /// Apples `f` to each item in this iterator
@@ -813,6 +2939,18 @@ pub trait TreeNodeIterator: Iterator {
         self,
         f: F,
     ) -> Result<Transformed<Vec<Self::Item>>>;
+
+    /// Same as [`Self::map_until_stop_and_collect`] but collects the items
+    /// into a [`SmallVec`](smallvec::SmallVec), which doesn't allocate on the
+    /// heap if there are at most `N` items.
+    #[cfg(feature = "smallvec")]
+    fn map_until_stop_and_collect_small<
+        const N: usize,
+        F: FnMut(Self::Item) -> Result<Transformed<Self::Item>>,
+    >(
+        self,
+        f: F,
+    ) -> Result<Transformed<smallvec::SmallVec<[Self::Item; N]>>>;
 }
 
 impl<I: Iterator> TreeNodeIterator for I {
@@ -852,10 +2990,74 @@ impl<I: Iterator> TreeNodeIterator for I {
         .collect::<Result<Vec<_>>>()
         .map(|data| Transformed::new(data, transformed, tnr))
     }
+
+    #[cfg(feature = "smallvec")]
+    fn map_until_stop_and_collect_small<
+        const N: usize,
+        F: FnMut(Self::Item) -> Result<Transformed<Self::Item>>,
+    >(
+        self,
+        mut f: F,
+    ) -> Result<Transformed<smallvec::SmallVec<[Self::Item; N]>>> {
+        let mut tnr = TreeNodeRecursion::Continue;
+        let mut transformed = false;
+        self.map(|item| match tnr {
+            TreeNodeRecursion::Continue | TreeNodeRecursion::Jump => {
+                f(item).map(|result| {
+                    tnr = result.tnr;
+                    transformed |= result.transformed;
+                    result.data
+                })
+            }
+            TreeNodeRecursion::Stop => Ok(item),
+        })
+        .collect::<Result<smallvec::SmallVec<_>>>()
+        .map(|data| Transformed::new(data, transformed, tnr))
+    }
 }
 
-/// Transformation helper to process a heterogeneous sequence of tree node containing
-/// expressions.
+/// Applies `f` to each node of `nodes` until `f` returns an error or
+/// `TreeNodeRecursion::Stop`, the remaining nodes are returned unchanged.
+///
+/// This is the same as [`TreeNodeIterator::map_until_stop_and_collect`] on the
+/// iterator of `nodes`.
+pub fn map_nodes_until_stop<T, F: FnMut(T) -> Result<Transformed<T>>>(
+    nodes: Vec<T>,
+    f: F,
+) -> Result<Transformed<Vec<T>>> {
+    nodes.into_iter().map_until_stop_and_collect(f)
+}
+
+/// Visits each of `trees` with a fresh visitor created by `make_visitor` on a
+/// rayon thread pool and combines the visitors with `reduce`.
+///
+/// The order in which visitors are combined is unspecified so `reduce` should be
+/// associative. An empty `trees` returns a visitor from `make_visitor`.
+#[cfg(feature = "parallel")]
+pub fn apply_many_parallel<'a, N, I, V>(
+    trees: I,
+    make_visitor: impl Fn() -> V + Sync + Send,
+    reduce: impl Fn(V, V) -> V + Sync + Send,
+) -> Result<V>
+where
+    N: TreeNode + Sync + 'a,
+    I: rayon::iter::IntoParallelIterator<Item = &'a N>,
+    V: TreeNodeVisitor<'a, Node = N> + Send,
+{
+    use rayon::iter::ParallelIterator;
+
+    trees
+        .into_par_iter()
+        .map(|tree| {
+            let mut visitor = make_visitor();
+            tree.visit(&mut visitor)?;
+            Ok(visitor)
+        })
+        .try_reduce(&make_visitor, |a, b| Ok(reduce(a, b)))
+}
+
+/// Transformation helper to process a heterogeneous sequence of tree node containing
+/// expressions.
 ///
 /// This macro is very similar to [TreeNodeIterator::map_until_stop_and_collect] to
 /// process nodes that are siblings, but it accepts an initial transformation (`F0`) and
@@ -950,6 +3152,96 @@ pub trait DynTreeNode {
         arc_self: Arc<Self>,
         new_children: Vec<Arc<Self>>,
     ) -> Result<Arc<Self>>;
+
+    /// Panics if any two direct children of the node are the same `Arc`
+    /// allocation.
+    ///
+    /// Sharing the same child in multiple positions can cause subtle bugs in
+    /// transformations, so this is a debug helper to catch such nodes early.
+    /// The check is only done in debug builds, in release builds this is a
+    /// no-op.
+    fn assert_unique_children(&self) {
+        if cfg!(debug_assertions) {
+            let children = self.arc_children();
+            for (i, child) in children.iter().enumerate() {
+                if let Some(j) = children[..i]
+                    .iter()
+                    .position(|c| std::ptr::addr_eq(Arc::as_ptr(c), Arc::as_ptr(child)))
+                {
+                    panic!("Children at positions {j} and {i} are the same allocation");
+                }
+            }
+        }
+    }
+
+    /// Calls `f` on each distinct node of the tree in top-down (pre-order)
+    /// fashion and caches the results in `cache`.
+    ///
+    /// Nodes are identified by their `Arc` allocation, so a subtree that is
+    /// shared in multiple positions of the tree, or that is already in `cache`
+    /// from a previous call, is analyzed only once and its descendants are not
+    /// visited again.
+    fn apply_memoized<V, F: FnMut(&Arc<Self>) -> Result<V>>(
+        self: &Arc<Self>,
+        cache: &mut Memoize<Self, V>,
+        mut f: F,
+    ) -> Result<()> {
+        self.apply(|node| {
+            if cache.get(node).is_some() {
+                return Ok(TreeNodeRecursion::Jump);
+            }
+            let value = f(node)?;
+            cache.insert(node, value);
+            Ok(TreeNodeRecursion::Continue)
+        })
+        .map(|_| ())
+    }
+}
+
+/// A cache of per-node analysis results of `Arc<K>` trees, keyed by the `Arc`
+/// allocation of the nodes, see [`DynTreeNode::apply_memoized`].
+///
+/// The cache keeps the nodes alive, so the allocations can't be reused by other
+/// nodes while they are cached.
+pub struct Memoize<K: ?Sized, V> {
+    entries: HashMap<*const (), (Arc<K>, V)>,
+}
+
+impl<K: ?Sized, V> Memoize<K, V> {
+    /// Creates a new, empty cache.
+    pub fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Returns the cached result of `node`.
+    pub fn get(&self, node: &Arc<K>) -> Option<&V> {
+        self.entries
+            .get(&(Arc::as_ptr(node) as *const ()))
+            .map(|(_, value)| value)
+    }
+
+    /// Returns the number of cached nodes.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if no node is cached.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn insert(&mut self, node: &Arc<K>, value: V) {
+        self.entries
+            .insert(Arc::as_ptr(node) as *const (), (Arc::clone(node), value));
+    }
+}
+
+impl<K: ?Sized, V> Default for Memoize<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 /// Blanket implementation for any `Arc<T>` where `T` implements [`DynTreeNode`]
@@ -986,6 +3278,73 @@ impl<T: DynTreeNode + ?Sized> TreeNode for Arc<T> {
             Ok(Transformed::no(self))
         }
     }
+
+    /// Same as the default [`TreeNode::transform_up`], but uses an explicit stack
+    /// instead of recursion so it doesn't overflow the call stack on deeply
+    /// nested trees.
+    fn transform_up<F: FnMut(Self) -> Result<Transformed<Self>>>(
+        self,
+        mut f: F,
+    ) -> Result<Transformed<Self>> {
+        /// A node whose children are being transformed
+        struct Frame<T: ?Sized> {
+            node: Arc<T>,
+            children: Vec<Arc<T>>,
+            new_children: Vec<Arc<T>>,
+            transformed: bool,
+            tnr: TreeNodeRecursion,
+        }
+
+        fn new_frame<T: DynTreeNode + ?Sized>(node: Arc<T>) -> Frame<T> {
+            let children = node.arc_children().into_iter().cloned().collect::<Vec<_>>();
+            Frame {
+                node,
+                new_children: Vec::with_capacity(children.len()),
+                children,
+                transformed: false,
+                tnr: TreeNodeRecursion::Continue,
+            }
+        }
+
+        let mut stack = vec![];
+        let mut frame = new_frame(self);
+        loop {
+            let next = frame.new_children.len();
+            if next < frame.children.len() && frame.tnr != TreeNodeRecursion::Stop {
+                let child = new_frame(Arc::clone(&frame.children[next]));
+                stack.push(std::mem::replace(&mut frame, child));
+                continue;
+            }
+
+            let Frame {
+                node,
+                children,
+                mut new_children,
+                transformed,
+                tnr,
+            } = frame;
+            // Rebuild the node only if any of its children changed, keeping the
+            // children after a `Stop` as they are
+            let node = if transformed {
+                new_children.extend(children[next..].iter().cloned());
+                node.with_new_arc_children(Arc::clone(&node), new_children)?
+            } else {
+                node
+            };
+            let result =
+                Transformed::new(node, transformed, tnr).transform_parent(&mut f)?;
+
+            match stack.pop() {
+                Some(mut parent) => {
+                    parent.new_children.push(result.data);
+                    parent.transformed |= result.transformed;
+                    parent.tnr = result.tnr;
+                    frame = parent;
+                }
+                None => return Ok(result),
+            }
+        }
+    }
 }
 
 /// Instead of implementing [`TreeNode`], it's recommended to implement a [`ConcreteTreeNode`] for
@@ -1000,6 +3359,120 @@ pub trait ConcreteTreeNode: Sized {
 
     /// Reattaches updated child nodes to the node, returning the updated node.
     fn with_new_children(self, children: Vec<Self>) -> Result<Self>;
+
+    /// Removes the children for which `keep` returns false, in top-down
+    /// (pre-order) fashion, so the subtrees of the removed children are not
+    /// visited.
+    ///
+    /// This relies on the [`Self::with_new_children`] implementation accepting
+    /// fewer children than the node originally had, which is not necessarily
+    /// the case for all node types.
+    fn filter_children<F: Fn(&Self) -> bool>(self, keep: F) -> Result<Transformed<Self>> {
+        fn filter_children_impl<N: ConcreteTreeNode, F: Fn(&N) -> bool>(
+            node: N,
+            keep: &F,
+        ) -> Result<Transformed<N>> {
+            let (node, children) = node.take_children();
+            if children.is_empty() {
+                return Ok(Transformed::no(node));
+            }
+            let count = children.len();
+            let children = children.into_iter().filter(keep).collect::<Vec<_>>();
+            let removed = children.len() < count;
+            children
+                .into_iter()
+                .map_until_stop_and_collect(|c| filter_children_impl(c, keep))?
+                .map_data(|children| node.with_new_children(children))
+                .map(|mut t| {
+                    t.transformed |= removed;
+                    t
+                })
+        }
+
+        filter_children_impl(self, &keep)
+    }
+
+    /// Same as [`TreeNode::map_children`] but `f` can replace each child with
+    /// any number of nodes, which are all attached to this node in place of
+    /// the child.
+    ///
+    /// This relies on the [`Self::with_new_children`] implementation accepting
+    /// a different number of children than the node originally had, which is
+    /// not necessarily the case for all node types.
+    fn map_children_flat<F: FnMut(Self) -> Result<Transformed<Vec<Self>>>>(
+        self,
+        mut f: F,
+    ) -> Result<Transformed<Self>> {
+        let (node, children) = self.take_children();
+        if children.is_empty() {
+            return Ok(Transformed::no(node));
+        }
+        let mut tnr = TreeNodeRecursion::Continue;
+        let mut transformed = false;
+        let mut new_children = Vec::with_capacity(children.len());
+        for child in children {
+            match tnr {
+                TreeNodeRecursion::Continue | TreeNodeRecursion::Jump => {
+                    let result = f(child)?;
+                    tnr = result.tnr;
+                    transformed |= result.transformed;
+                    new_children.extend(result.data);
+                }
+                TreeNodeRecursion::Stop => new_children.push(child),
+            }
+        }
+        Ok(Transformed::new(
+            node.with_new_children(new_children)?,
+            transformed,
+            tnr,
+        ))
+    }
+
+    /// Same as [`TreeNode::rewrite`] but calls [`TreeNodeRewriter::f_up_expand`]
+    /// instead of `f_up` on all nodes except this one, so the rewriter can
+    /// replace a node with any number of sibling nodes.
+    ///
+    /// See [`Self::map_children_flat`] for the requirements on the number of
+    /// children.
+    fn rewrite_flat<R: TreeNodeRewriter<Node = Self>>(
+        self,
+        rewriter: &mut R,
+    ) -> Result<Transformed<Self>> {
+        fn rewrite_flat_impl<N: ConcreteTreeNode, R: TreeNodeRewriter<Node = N>>(
+            node: N,
+            rewriter: &mut R,
+        ) -> Result<Transformed<Vec<N>>> {
+            if !rewriter.should_visit(&node) {
+                return Ok(node
+                    .map_children_flat(|c| rewrite_flat_impl(c, rewriter))?
+                    .update_data(|node| vec![node]));
+            }
+            let t = rewriter.f_down(node)?.transform_children(|n| {
+                n.map_children_flat(|c| rewrite_flat_impl(c, rewriter))
+            })?;
+            match t.tnr {
+                TreeNodeRecursion::Continue => {
+                    rewriter.f_up_expand(t.data).map(|mut result| {
+                        result.transformed |= t.transformed;
+                        result
+                    })
+                }
+                TreeNodeRecursion::Jump | TreeNodeRecursion::Stop => {
+                    Ok(t.update_data(|node| vec![node]))
+                }
+            }
+        }
+
+        if !rewriter.should_visit(&self) {
+            return self.map_children_flat(|c| rewrite_flat_impl(c, rewriter));
+        }
+        rewriter
+            .f_down(self)?
+            .transform_children(|n| {
+                n.map_children_flat(|c| rewrite_flat_impl(c, rewriter))
+            })?
+            .transform_parent(|n| rewriter.f_up(n))
+    }
 }
 
 impl<T: ConcreteTreeNode> TreeNode for T {
@@ -1028,16 +3501,23 @@ impl<T: ConcreteTreeNode> TreeNode for T {
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use std::cell::Cell;
+    use std::collections::{HashMap, HashSet};
     use std::fmt::Display;
+    use std::hash::Hash;
+    use std::ops::ControlFlow;
+    use std::rc::Rc;
+    use std::sync::Arc;
 
     use crate::tree_node::{
-        Transformed, TreeNode, TreeNodeIterator, TreeNodeRecursion, TreeNodeRewriter,
-        TreeNodeVisitor,
+        map_nodes_until_stop, BoundedRewriter, ConcreteTreeNode, DepthTrackingVisitor,
+        DynTreeNode, LazyNode, Memoize, RewriteRecorder, StepwiseTransform, SubtreeStats,
+        Template, Transformed, TreeNode, TreeNodeContextRewriter, TreeNodeIterator,
+        TreeNodeRecursion, TreeNodeRewriter, TreeNodeVisitor,
     };
-    use crate::Result;
+    use crate::{DataFusionError, Result};
 
-    #[derive(Debug, Eq, Hash, PartialEq)]
+    #[derive(Clone, Debug, Eq, Hash, PartialEq)]
     struct TestTreeNode<T> {
         children: Vec<TestTreeNode<T>>,
         data: T,
@@ -1532,6 +4012,13 @@ mod tests {
             .collect()
     }
 
+    fn up_visits(visits: Vec<String>) -> Vec<String> {
+        visits
+            .into_iter()
+            .filter(|v| v.starts_with("f_up"))
+            .collect()
+    }
+
     type TestVisitorF<T> = Box<dyn FnMut(&TestTreeNode<T>) -> Result<TreeNodeRecursion>>;
 
     struct TestVisitor<T> {
@@ -1613,6 +4100,40 @@ mod tests {
         };
     }
 
+    macro_rules! test_apply_up {
+        ($NAME:ident, $F:expr, $EXPECTED_VISITS:expr) => {
+            #[test]
+            fn $NAME() -> Result<()> {
+                let tree = test_tree();
+                let mut visits = vec![];
+                tree.apply_up(|node| {
+                    visits.push(format!("f_up({})", node.data));
+                    $F(node)
+                })?;
+                assert_eq!(visits, $EXPECTED_VISITS);
+
+                Ok(())
+            }
+        };
+    }
+
+    macro_rules! test_apply_iterative {
+        ($NAME:ident, $F:expr, $EXPECTED_VISITS:expr) => {
+            #[test]
+            fn $NAME() -> Result<()> {
+                let tree = test_tree();
+                let mut visits = vec![];
+                tree.apply_iterative(|node| {
+                    visits.push(format!("f_down({})", node.data));
+                    $F(node)
+                })?;
+                assert_eq!(visits, $EXPECTED_VISITS);
+
+                Ok(())
+            }
+        };
+    }
+
     type TestRewriterF<T> =
         Box<dyn FnMut(TestTreeNode<T>) -> Result<Transformed<TestTreeNode<T>>>>;
 
@@ -1794,6 +4315,54 @@ mod tests {
         down_visits(f_down_stop_on_e_visits())
     );
 
+    test_apply_up!(test_apply_up, visit_continue, up_visits(all_visits()));
+    test_apply_up!(
+        test_apply_up_f_up_jump_on_a,
+        visit_event_on("a", TreeNodeRecursion::Jump),
+        up_visits(f_up_jump_on_a_visits())
+    );
+    test_apply_up!(
+        test_apply_up_f_up_jump_on_e,
+        visit_event_on("e", TreeNodeRecursion::Jump),
+        up_visits(f_up_jump_on_e_visits())
+    );
+    test_apply_up!(
+        test_apply_up_f_up_stop_on_a,
+        visit_event_on("a", TreeNodeRecursion::Stop),
+        up_visits(f_up_stop_on_a_visits())
+    );
+    test_apply_up!(
+        test_apply_up_f_up_stop_on_e,
+        visit_event_on("e", TreeNodeRecursion::Stop),
+        up_visits(f_up_stop_on_e_visits())
+    );
+
+    test_apply_iterative!(
+        test_apply_iterative,
+        visit_continue,
+        down_visits(all_visits())
+    );
+    test_apply_iterative!(
+        test_apply_iterative_f_down_jump_on_a,
+        visit_event_on("a", TreeNodeRecursion::Jump),
+        down_visits(f_down_jump_on_a_visits())
+    );
+    test_apply_iterative!(
+        test_apply_iterative_f_down_jump_on_e,
+        visit_event_on("e", TreeNodeRecursion::Jump),
+        down_visits(f_down_jump_on_e_visits())
+    );
+    test_apply_iterative!(
+        test_apply_iterative_f_down_stop_on_a,
+        visit_event_on("a", TreeNodeRecursion::Stop),
+        down_visits(f_down_stop_on_a_visits())
+    );
+    test_apply_iterative!(
+        test_apply_iterative_f_down_stop_on_e,
+        visit_event_on("e", TreeNodeRecursion::Stop),
+        down_visits(f_down_stop_on_e_visits())
+    );
+
     rewrite_test!(
         test_rewrite,
         transform_yes("f_down"),
@@ -2086,4 +4655,2048 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_transform_down_mut_with_ctx() -> Result<()> {
+        let protected = Rc::new(HashSet::from(["e", "g"]));
+        let tree = test_tree();
+        let mut f = |node: TestTreeNode<String>, protected: &HashSet<&str>| {
+            Ok(if protected.contains(node.data.as_str()) {
+                Transformed::no(node)
+            } else {
+                Transformed::yes(TestTreeNode::new(
+                    node.children,
+                    format!("f_down({})", node.data),
+                ))
+            })
+        };
+        let transformed = tree.transform_down_mut_with_ctx(protected.as_ref(), &mut f)?;
+
+        let node_a = TestTreeNode::new(vec![], "f_down(a)".to_string());
+        let node_b = TestTreeNode::new(vec![], "f_down(b)".to_string());
+        let node_d = TestTreeNode::new(vec![node_a], "f_down(d)".to_string());
+        let node_c = TestTreeNode::new(vec![node_b, node_d], "f_down(c)".to_string());
+        let node_e = TestTreeNode::new(vec![node_c], "e".to_string());
+        let node_h = TestTreeNode::new(vec![], "f_down(h)".to_string());
+        let node_g = TestTreeNode::new(vec![node_h], "g".to_string());
+        let node_f = TestTreeNode::new(vec![node_e, node_g], "f_down(f)".to_string());
+        let node_i = TestTreeNode::new(vec![node_f], "f_down(i)".to_string());
+        let expected = TestTreeNode::new(vec![node_i], "f_down(j)".to_string());
+        assert_eq!(transformed, Transformed::yes(expected));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_nodes_at_depth() {
+        let tree = test_tree();
+        let data = |depth| {
+            tree.nodes_at_depth(depth)
+                .into_iter()
+                .map(|n| n.data.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(data(0), vec!["j"]);
+        assert_eq!(data(2), vec!["f"]);
+        assert_eq!(data(3), vec!["e", "g"]);
+        assert_eq!(data(6), vec!["a"]);
+        assert!(data(7).is_empty());
+    }
+
+    #[test]
+    fn test_transform_up_collect_errors() -> Result<()> {
+        let tree = test_tree();
+        #[allow(clippy::result_large_err)]
+        let mut f = |node: TestTreeNode<String>| {
+            if node.data == "a" || node.data == "g" {
+                let e = DataFusionError::Execution(format!("error on {}", node.data));
+                Err((node, e))
+            } else {
+                Ok(transform_yes("f_up")(node).unwrap())
+            }
+        };
+        let (transformed, errors) = tree.transform_up_collect_errors(&mut f)?;
+
+        let node_a = TestTreeNode::new(vec![], "a".to_string());
+        let node_b = TestTreeNode::new(vec![], "f_up(b)".to_string());
+        let node_d = TestTreeNode::new(vec![node_a], "f_up(d)".to_string());
+        let node_c = TestTreeNode::new(vec![node_b, node_d], "f_up(c)".to_string());
+        let node_e = TestTreeNode::new(vec![node_c], "f_up(e)".to_string());
+        let node_h = TestTreeNode::new(vec![], "f_up(h)".to_string());
+        let node_g = TestTreeNode::new(vec![node_h], "g".to_string());
+        let node_f = TestTreeNode::new(vec![node_e, node_g], "f_up(f)".to_string());
+        let node_i = TestTreeNode::new(vec![node_f], "f_up(i)".to_string());
+        let expected = TestTreeNode::new(vec![node_i], "f_up(j)".to_string());
+        assert_eq!(transformed, Transformed::yes(expected));
+
+        let errors = errors.iter().map(|e| e.message()).collect::<Vec<_>>();
+        assert_eq!(errors, vec!["error on a", "error on g"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_exists() -> Result<()> {
+        let tree = test_tree();
+
+        // matching leaf, stops right after visiting it
+        let mut visits = vec![];
+        assert!(tree.exists(|n| {
+            visits.push(n.data.clone());
+            Ok(n.data == "b")
+        })?);
+        assert_eq!(visits, vec!["j", "i", "f", "e", "c", "b"]);
+
+        // matching root, no other nodes are visited
+        let mut visits = vec![];
+        assert!(tree.exists(|n| {
+            visits.push(n.data.clone());
+            Ok(n.data == "j")
+        })?);
+        assert_eq!(visits, vec!["j"]);
+
+        // no match, all nodes are visited
+        let mut visits = 0;
+        assert!(!tree.exists(|n| {
+            visits += 1;
+            Ok(n.data == "x")
+        })?);
+        assert_eq!(visits, 10);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_controlled_iter() {
+        let tree = test_tree();
+        let mut iter = tree.controlled_iter();
+        let mut visits = vec![];
+        while let Some(node) = iter.next() {
+            visits.push(node.data.as_str());
+            if node.data == "e" {
+                iter.prune();
+            }
+        }
+        assert_eq!(visits, vec!["j", "i", "f", "e", "g", "h"]);
+
+        let mut iter = tree.controlled_iter();
+        let mut visits = vec![];
+        while let Some(node) = iter.next() {
+            visits.push(node.data.as_str());
+            if node.data == "b" {
+                iter.stop();
+            }
+        }
+        assert_eq!(visits, vec!["j", "i", "f", "e", "c", "b"]);
+
+        let visits = tree
+            .controlled_iter()
+            .map(|n| n.data.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            visits,
+            vec!["j", "i", "f", "e", "c", "b", "d", "a", "g", "h"]
+        );
+    }
+
+    #[test]
+    fn test_count() {
+        let tree = test_tree();
+        assert_eq!(tree.count(|n| n.data.len() == 1), 10);
+        assert_eq!(tree.count(|n| n.children.is_empty()), 3);
+        assert_eq!(tree.count(|n| n.data == "x"), 0);
+
+        let leaf = TestTreeNode::new(vec![], "a".to_string());
+        assert_eq!(leaf.count(|_| true), 1);
+    }
+
+    #[test]
+    fn test_transform_up_kind_budget() -> Result<()> {
+        let tree = test_tree();
+        // the kind of a node is the number of its children, only the first node
+        // with exactly 1 child (`d`) should be transformed in post-order
+        let budgets = HashMap::from([(1, 1)]);
+        let transformed = tree.transform_up_kind_budget(
+            budgets,
+            |n| n.children.len(),
+            &mut transform_yes("f_up"),
+        )?;
+
+        let node_a = TestTreeNode::new(vec![], "f_up(a)".to_string());
+        let node_b = TestTreeNode::new(vec![], "f_up(b)".to_string());
+        let node_d = TestTreeNode::new(vec![node_a], "f_up(d)".to_string());
+        let node_c = TestTreeNode::new(vec![node_b, node_d], "f_up(c)".to_string());
+        let node_e = TestTreeNode::new(vec![node_c], "e".to_string());
+        let node_h = TestTreeNode::new(vec![], "f_up(h)".to_string());
+        let node_g = TestTreeNode::new(vec![node_h], "g".to_string());
+        let node_f = TestTreeNode::new(vec![node_e, node_g], "f_up(f)".to_string());
+        let node_i = TestTreeNode::new(vec![node_f], "i".to_string());
+        let expected = TestTreeNode::new(vec![node_i], "j".to_string());
+        assert_eq!(transformed, Transformed::yes(expected));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_iterative_deep_tree() -> Result<()> {
+        // a tree this deep overflows the stack with the recursive `apply`
+        let depth = 100_000;
+        let mut tree = TestTreeNode::new(vec![], 0);
+        for i in 1..depth {
+            tree = TestTreeNode::new(vec![tree], i);
+        }
+
+        let mut visits = 0;
+        let tnr = tree.apply_iterative(|_| {
+            visits += 1;
+            Ok(TreeNodeRecursion::Continue)
+        })?;
+        assert_eq!(tnr, TreeNodeRecursion::Continue);
+        assert_eq!(visits, depth);
+
+        // dismantle the tree iteratively as the default recursive drop would also
+        // overflow the stack
+        while let Some(child) = tree.children.pop() {
+            tree = child;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transformed_and_then() -> Result<()> {
+        let t = Transformed::no(1)
+            .and_then(|n| Ok(Transformed::yes(n + 1)))?
+            .and_then(|n| Ok(Transformed::no(n * 10)))?;
+        assert_eq!(t, Transformed::yes(20));
+
+        let t = Transformed::no(1).and_then(|n| Ok(Transformed::no(n + 1)))?;
+        assert_eq!(t, Transformed::no(2));
+
+        let t = Transformed::new(1, false, TreeNodeRecursion::Stop)
+            .and_then(|n| Ok(Transformed::yes(n + 1)))?;
+        assert_eq!(t, Transformed::new(2, true, TreeNodeRecursion::Stop));
+
+        let t = Transformed::yes(1)
+            .and_then(|n| Ok(Transformed::new(n + 1, false, TreeNodeRecursion::Stop)))?
+            .and_then(|n| Ok(Transformed::new(n + 1, false, TreeNodeRecursion::Jump)))?;
+        assert_eq!(t, Transformed::new(3, true, TreeNodeRecursion::Stop));
+
+        let t = Transformed::no(1)
+            .and_then(|n| Ok(Transformed::new(n + 1, false, TreeNodeRecursion::Jump)))?
+            .and_then(|n| Ok(Transformed::no(n + 1)))?;
+        assert_eq!(t, Transformed::new(3, false, TreeNodeRecursion::Jump));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_fanout() {
+        assert_eq!(test_tree().max_fanout(), 2);
+        assert_eq!(TestTreeNode::new(vec![], "a".to_string()).max_fanout(), 0);
+
+        let wide = TestTreeNode::new(
+            (0..5)
+                .map(|i| TestTreeNode::new(vec![], i.to_string()))
+                .collect(),
+            "root".to_string(),
+        );
+        assert_eq!(
+            TestTreeNode::new(vec![wide], "top".to_string()).max_fanout(),
+            5
+        );
+    }
+
+    #[test]
+    fn test_transform_with_count() -> Result<()> {
+        let rename = |node: TestTreeNode<String>| {
+            Ok(if node.data == "d" || node.data == "g" {
+                Transformed::yes(TestTreeNode::new(
+                    node.children,
+                    format!("f_{}", node.data),
+                ))
+            } else {
+                Transformed::no(node)
+            })
+        };
+
+        let (tree, count) = test_tree().transform_up_with_count(&rename)?;
+        assert_eq!(count, 2);
+        assert_eq!(tree.count(|n| n.data.starts_with("f_")), 2);
+
+        let (tree, count) = test_tree().transform_down_with_count(&rename)?;
+        assert_eq!(count, 2);
+        assert_eq!(tree.count(|n| n.data.starts_with("f_")), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transform_down_up_with_leaf() -> Result<()> {
+        let mut down_leaves = vec![];
+        let mut up_leaves = vec![];
+        test_tree().transform_down_up_with_leaf(
+            |n, is_leaf| {
+                down_leaves.push((n.data.clone(), is_leaf));
+                Ok(Transformed::no(n))
+            },
+            |n, is_leaf| {
+                up_leaves.push((n.data.clone(), is_leaf));
+                Ok(Transformed::no(n))
+            },
+        )?;
+
+        let expected = ["a", "b", "h"];
+        assert_eq!(down_leaves.len(), 10);
+        assert_eq!(up_leaves.len(), 10);
+        for (data, is_leaf) in down_leaves.iter().chain(&up_leaves) {
+            assert_eq!(*is_leaf, expected.contains(&data.as_str()), "node {data}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_depth_tracking_visitor() -> Result<()> {
+        let tree = test_tree();
+
+        // `a` is the deepest node, 6 levels below the root `j`
+        let mut visitor = DepthTrackingVisitor::new(TestVisitor::new(
+            Box::new(visit_event_on("a", TreeNodeRecursion::Stop)),
+            Box::new(visit_continue),
+        ));
+        tree.visit(&mut visitor)?;
+        assert_eq!(visitor.depth(), 6);
+
+        // `Jump` in `f_up` on `a` bypasses `f_up` on `d`, `c` and `e`
+        let mut visitor = DepthTrackingVisitor::new(TestVisitor::new(
+            Box::new(visit_event_on("h", TreeNodeRecursion::Stop)),
+            Box::new(visit_event_on("a", TreeNodeRecursion::Jump)),
+        ));
+        tree.visit(&mut visitor)?;
+        assert_eq!(visitor.depth(), 4);
+        assert_eq!(
+            visitor.into_inner().visits,
+            f_up_jump_on_a_visits()
+                .into_iter()
+                .take_while(|v| v != "f_up(h)")
+                .collect::<Vec<_>>()
+        );
+
+        // The depth is back to the root after a complete traversal
+        let mut visitor = DepthTrackingVisitor::new(TestVisitor::new(
+            Box::new(visit_continue),
+            Box::new(visit_continue),
+        ));
+        tree.visit(&mut visitor)?;
+        assert_eq!(visitor.depth(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_root_to_leaf_paths() {
+        let tree = test_tree();
+        let paths = tree
+            .root_to_leaf_paths()
+            .into_iter()
+            .map(|path| path.into_iter().map(|n| n.data.as_str()).collect())
+            .collect::<Vec<Vec<_>>>();
+        assert_eq!(
+            paths,
+            vec![
+                vec!["j", "i", "f", "e", "c", "b"],
+                vec!["j", "i", "f", "e", "c", "d", "a"],
+                vec!["j", "i", "f", "g", "h"],
+            ]
+        );
+
+        let leaf = TestTreeNode::new(vec![], "a".to_string());
+        assert_eq!(leaf.root_to_leaf_paths(), vec![vec![&leaf]]);
+    }
+
+    #[test]
+    fn test_transform_with_payload() -> Result<()> {
+        // the down-payload is the depth of the node, the up-payload is the size of
+        // the subtree
+        let (tree, size) = test_tree().transform_with_payload(
+            &mut |n, depth| {
+                let child_depths = vec![depth + 1; n.children.len()];
+                Ok((
+                    TestTreeNode::new(n.children, format!("{}@{depth}", n.data)),
+                    child_depths,
+                ))
+            },
+            0,
+            &mut |n, child_sizes| {
+                let size = child_sizes.into_iter().sum::<usize>() + 1;
+                Ok((
+                    TestTreeNode::new(n.children, format!("{}:{size}", n.data)),
+                    size,
+                ))
+            },
+        )?;
+        assert_eq!(size, 10);
+
+        let mut nodes = vec![];
+        tree.apply(|n| {
+            nodes.push(n.data.clone());
+            Ok(TreeNodeRecursion::Continue)
+        })?;
+        assert_eq!(
+            nodes,
+            vec![
+                "j@0:10", "i@1:9", "f@2:8", "e@3:5", "c@4:4", "b@5:1", "d@5:2", "a@6:1",
+                "g@3:2", "h@4:1"
+            ]
+        );
+
+        // `f_down` must return a payload for every child
+        let result = test_tree().transform_with_payload(
+            &mut |n, _| Ok((n, vec![])),
+            (),
+            &mut |n, _| Ok((n, ())),
+        );
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transformed_flatten() {
+        use TreeNodeRecursion::*;
+
+        let tnrs = [Continue, Jump, Stop];
+        for outer_tnr in tnrs {
+            for inner_tnr in tnrs {
+                let expected_tnr = if outer_tnr == Stop || inner_tnr == Stop {
+                    Stop
+                } else if outer_tnr == Jump || inner_tnr == Jump {
+                    Jump
+                } else {
+                    Continue
+                };
+                for (outer_transformed, inner_transformed) in
+                    [(false, false), (false, true), (true, false), (true, true)]
+                {
+                    let nested = Transformed::new(
+                        Transformed::new(1, inner_transformed, inner_tnr),
+                        outer_transformed,
+                        outer_tnr,
+                    );
+                    assert_eq!(
+                        nested.flatten(),
+                        Transformed::new(
+                            1,
+                            outer_transformed || inner_transformed,
+                            expected_tnr
+                        ),
+                        "outer: {outer_tnr:?}, inner: {inner_tnr:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_transform_up_with_changed_roots() -> Result<()> {
+        fn rename_nodes(
+            names: &'static [&'static str],
+        ) -> impl FnMut(TestTreeNode<String>) -> Result<Transformed<TestTreeNode<String>>>
+        {
+            move |node| {
+                Ok(if names.contains(&node.data.as_str()) {
+                    Transformed::yes(TestTreeNode::new(
+                        node.children,
+                        format!("f_up({})", node.data),
+                    ))
+                } else {
+                    Transformed::no(node)
+                })
+            }
+        }
+
+        // the ancestors of `a` change only due to `a`
+        let (transformed, roots) =
+            test_tree().transform_up_with_changed_roots(rename_nodes(&["a"]))?;
+        assert!(transformed.transformed);
+        assert_eq!(roots, vec![vec![0, 0, 0, 0, 1, 0]]);
+
+        // `c` covers the change of `a`
+        let (_, roots) =
+            test_tree().transform_up_with_changed_roots(rename_nodes(&["a", "c"]))?;
+        assert_eq!(roots, vec![vec![0, 0, 0, 0]]);
+
+        let (_, roots) =
+            test_tree().transform_up_with_changed_roots(rename_nodes(&["b", "h"]))?;
+        assert_eq!(roots, vec![vec![0, 0, 0, 0, 0], vec![0, 0, 1, 0]]);
+
+        let (_, roots) =
+            test_tree().transform_up_with_changed_roots(rename_nodes(&["a", "j"]))?;
+        assert_eq!(roots, vec![Vec::<usize>::new()]);
+
+        let (transformed, roots) =
+            test_tree().transform_up_with_changed_roots(rename_nodes(&[]))?;
+        assert!(!transformed.transformed);
+        assert!(roots.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewrite_with_context() -> Result<()> {
+        // Tags each node with the depth that `f_down` passes to `f_up`
+        struct DepthTaggingRewriter {
+            depth: usize,
+        }
+
+        impl TreeNodeContextRewriter for DepthTaggingRewriter {
+            type Node = TestTreeNode<String>;
+            type Context = usize;
+
+            fn f_down(
+                &mut self,
+                node: Self::Node,
+            ) -> Result<(Transformed<Self::Node>, Self::Context)> {
+                let depth = self.depth;
+                self.depth = depth + 1;
+                Ok((Transformed::no(node), depth))
+            }
+
+            fn f_up(
+                &mut self,
+                node: Self::Node,
+                depth: Self::Context,
+            ) -> Result<Transformed<Self::Node>> {
+                self.depth = depth;
+                Ok(Transformed::yes(TestTreeNode::new(
+                    node.children,
+                    format!("f_up({})@{depth}", node.data),
+                )))
+            }
+        }
+
+        let mut rewriter = DepthTaggingRewriter { depth: 0 };
+        let transformed = test_tree().rewrite_with_context(&mut rewriter)?;
+        assert!(transformed.transformed);
+        assert_eq!(rewriter.depth, 0);
+
+        let mut nodes = vec![];
+        transformed.data.apply(|n| {
+            nodes.push(n.data.clone());
+            Ok(TreeNodeRecursion::Continue)
+        })?;
+        assert_eq!(
+            nodes,
+            vec![
+                "f_up(j)@0",
+                "f_up(i)@1",
+                "f_up(f)@2",
+                "f_up(e)@3",
+                "f_up(c)@4",
+                "f_up(b)@5",
+                "f_up(d)@5",
+                "f_up(a)@6",
+                "f_up(g)@3",
+                "f_up(h)@4",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_map() {
+        let tree = test_tree();
+
+        let mut visits = 0;
+        let found = tree.find_map(|n| {
+            visits += 1;
+            (n.children.len() == 2).then(|| format!("{} is binary", n.data))
+        });
+        assert_eq!(found, Some("f is binary".to_string()));
+        // the traversal stops at `f`
+        assert_eq!(visits, 3);
+
+        let found = tree.find_map(|n| (n.data == "z").then_some(()));
+        assert_eq!(found, None);
+    }
+
+    #[test]
+    fn test_visit_both() -> Result<()> {
+        let tree = test_tree();
+
+        let mut v1 = TestVisitor::new(Box::new(visit_continue), Box::new(visit_continue));
+        let mut v2 = TestVisitor::new(Box::new(visit_continue), Box::new(visit_continue));
+        let tnr = tree.visit_both(&mut v1, &mut v2)?;
+        assert_eq!(tnr, TreeNodeRecursion::Continue);
+        assert_eq!(v1.visits, all_visits());
+        assert_eq!(v2.visits, all_visits());
+
+        // `Jump` from either visitor prunes the subtree for both
+        let mut v1 = TestVisitor::new(Box::new(visit_continue), Box::new(visit_continue));
+        let mut v2 = TestVisitor::new(
+            Box::new(visit_event_on("e", TreeNodeRecursion::Jump)),
+            Box::new(visit_continue),
+        );
+        tree.visit_both(&mut v1, &mut v2)?;
+        assert_eq!(v1.visits, f_down_jump_on_e_visits());
+        assert_eq!(v2.visits, f_down_jump_on_e_visits());
+
+        // `Stop` from either visitor stops the traversal for both
+        let mut v1 = TestVisitor::new(
+            Box::new(visit_continue),
+            Box::new(visit_event_on("a", TreeNodeRecursion::Stop)),
+        );
+        let mut v2 = TestVisitor::new(Box::new(visit_continue), Box::new(visit_continue));
+        let tnr = tree.visit_both(&mut v1, &mut v2)?;
+        assert_eq!(tnr, TreeNodeRecursion::Stop);
+        assert_eq!(v1.visits, f_up_stop_on_a_visits());
+        assert_eq!(v2.visits, f_up_stop_on_a_visits());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_subtrees() -> Result<()> {
+        let placeholder = |n: &TestTreeNode<String>| {
+            TestTreeNode::new(vec![], format!("p({})", n.data))
+        };
+
+        let (transformed, subtrees) =
+            test_tree().extract_subtrees(&|n| n.data == "c", &placeholder)?;
+        assert!(transformed.transformed);
+        assert_eq!(transformed.tnr, TreeNodeRecursion::Continue);
+
+        let node_p = TestTreeNode::new(vec![], "p(c)".to_string());
+        let node_e = TestTreeNode::new(vec![node_p], "e".to_string());
+        let node_h = TestTreeNode::new(vec![], "h".to_string());
+        let node_g = TestTreeNode::new(vec![node_h], "g".to_string());
+        let node_f = TestTreeNode::new(vec![node_e, node_g], "f".to_string());
+        let node_i = TestTreeNode::new(vec![node_f], "i".to_string());
+        let expected_tree = TestTreeNode::new(vec![node_i], "j".to_string());
+        assert_eq!(transformed.data, expected_tree);
+
+        let node_a = TestTreeNode::new(vec![], "a".to_string());
+        let node_b = TestTreeNode::new(vec![], "b".to_string());
+        let node_d = TestTreeNode::new(vec![node_a], "d".to_string());
+        let node_c = TestTreeNode::new(vec![node_b, node_d], "c".to_string());
+        assert_eq!(subtrees, vec![node_c]);
+
+        // matching nodes in the extracted subtrees are not visited
+        let (_, subtrees) = test_tree().extract_subtrees(
+            &|n| ["c", "a", "g"].contains(&n.data.as_str()),
+            &placeholder,
+        )?;
+        assert_eq!(
+            subtrees.iter().map(|n| n.data.as_str()).collect::<Vec<_>>(),
+            vec!["c", "g"]
+        );
+
+        let (transformed, subtrees) =
+            test_tree().extract_subtrees(&|_| false, &placeholder)?;
+        assert_eq!(transformed, Transformed::no(test_tree()));
+        assert!(subtrees.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transform_down_jump_on_replacement() -> Result<()> {
+        // Wrapping `h` into `f_down(h)` and continuing with the recursion would
+        // visit `h` again and wrap it infinitely, but a `Jump` prunes the
+        // replacement
+        let transformed = test_tree().transform_down(|n| {
+            Ok(if n.data == "h" {
+                let wrapper = TestTreeNode::new(vec![n], "f_down(h)".to_string());
+                Transformed::new(wrapper, true, TreeNodeRecursion::Jump)
+            } else {
+                Transformed::no(n)
+            })
+        })?;
+        assert!(transformed.transformed);
+        assert_eq!(transformed.tnr, TreeNodeRecursion::Continue);
+        assert_eq!(transformed.data.count(|n| n.data == "f_down(h)"), 1);
+        assert_eq!(transformed.data.count(|n| n.data == "h"), 1);
+
+        Ok(())
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct DynTestNode {
+        data: String,
+        children: Vec<Arc<DynTestNode>>,
+    }
+
+    impl DynTestNode {
+        fn new(children: Vec<Arc<DynTestNode>>, data: &str) -> Arc<Self> {
+            Arc::new(Self {
+                data: data.to_string(),
+                children,
+            })
+        }
+    }
+
+    impl DynTreeNode for DynTestNode {
+        fn arc_children(&self) -> Vec<&Arc<Self>> {
+            self.children.iter().collect()
+        }
+
+        fn with_new_arc_children(
+            &self,
+            _arc_self: Arc<Self>,
+            new_children: Vec<Arc<Self>>,
+        ) -> Result<Arc<Self>> {
+            Ok(Arc::new(Self {
+                data: self.data.clone(),
+                children: new_children,
+            }))
+        }
+    }
+
+    // Drop the children iteratively to be able to drop deep trees
+    impl Drop for DynTestNode {
+        fn drop(&mut self) {
+            let mut children = std::mem::take(&mut self.children);
+            while let Some(child) = children.pop() {
+                if let Ok(mut child) = Arc::try_unwrap(child) {
+                    children.append(&mut child.children);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_assert_unique_children() {
+        let leaf = || DynTestNode::new(vec![], "leaf");
+        let node = DynTestNode::new(vec![leaf(), leaf()], "node");
+        node.assert_unique_children();
+    }
+
+    // the check is a no-op in release builds
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "Children at positions 0 and 2 are the same allocation")]
+    fn test_assert_unique_children_aliasing() {
+        let shared = DynTestNode::new(vec![], "leaf");
+        let node = DynTestNode::new(
+            vec![
+                Arc::clone(&shared),
+                DynTestNode::new(vec![], "leaf"),
+                shared,
+            ],
+            "node",
+        );
+        node.assert_unique_children();
+    }
+
+    #[test]
+    fn test_subtree_stats() {
+        let tree = test_tree();
+        let stats = tree.subtree_stats();
+        assert_eq!(stats.len(), 10);
+        assert_eq!(
+            stats[&vec![]],
+            SubtreeStats {
+                size: 10,
+                height: 7,
+                leaves: 3,
+            }
+        );
+        // f
+        assert_eq!(
+            stats[&vec![0, 0]],
+            SubtreeStats {
+                size: 8,
+                height: 5,
+                leaves: 3,
+            }
+        );
+        // h
+        assert_eq!(
+            stats[&vec![0, 0, 1, 0]],
+            SubtreeStats {
+                size: 1,
+                height: 1,
+                leaves: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_tree_node_recursion_then() {
+        use TreeNodeRecursion::*;
+
+        let cases = [
+            (Continue, Continue, Continue),
+            (Continue, Jump, Jump),
+            (Continue, Stop, Stop),
+            (Jump, Continue, Jump),
+            (Jump, Jump, Jump),
+            (Jump, Stop, Jump),
+            (Stop, Continue, Stop),
+            (Stop, Jump, Stop),
+            (Stop, Stop, Stop),
+        ];
+        for (first, second, expected) in cases {
+            assert_eq!(first.then(second), expected, "{first:?}.then({second:?})");
+        }
+    }
+
+    #[test]
+    fn test_stepwise_transform() -> Result<()> {
+        let mut stepwise = StepwiseTransform::new(test_tree(), transform_yes("f_up"));
+        let mut steps = 0;
+        while let Some(t) = stepwise.step()? {
+            assert!(t.transformed);
+            steps += 1;
+            if steps == 1 {
+                // the first node in post-order is `b`
+                let tree = stepwise.tree().unwrap();
+                assert!(tree.exists(|n| Ok(n.data == "f_up(b)"))?);
+                assert!(!tree.exists(|n| Ok(n.data == "f_up(d)"))?);
+            }
+        }
+        assert_eq!(steps, 10);
+        assert!(stepwise.step()?.is_none());
+        assert_eq!(stepwise.into_tree(), Some(transformed_up_tree()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_collect_pre_order() {
+        let tree = test_tree();
+        let nodes = tree
+            .collect_pre_order()
+            .into_iter()
+            .map(|n| n.data.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            nodes,
+            vec!["j", "i", "f", "e", "c", "b", "d", "a", "g", "h"]
+        );
+    }
+
+    #[test]
+    fn test_collect_post_order() {
+        let tree = test_tree();
+        let nodes = tree
+            .collect_post_order()
+            .into_iter()
+            .map(|n| n.data.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            nodes,
+            vec!["b", "a", "d", "c", "e", "h", "g", "f", "i", "j"]
+        );
+    }
+
+    #[test]
+    fn test_transformed_expect_transformed() {
+        assert_eq!(
+            Transformed::yes(1)
+                .expect_transformed("must transform")
+                .unwrap(),
+            1
+        );
+        assert!(Transformed::no(1)
+            .expect_transformed("must transform")
+            .unwrap_err()
+            .strip_backtrace()
+            .starts_with("Internal error: must transform"));
+    }
+
+    #[test]
+    fn test_match_template() -> Result<()> {
+        let leaf = |data: &str| TestTreeNode::new(vec![], data.to_string());
+        let node_eq =
+            |n: &TestTreeNode<String>, t: &TestTreeNode<String>| n.data == t.data;
+        let template = Template::node(
+            TestTreeNode::new(vec![leaf("_"), leaf("_")], "c".to_string()),
+            vec![Template::hole("x"), Template::hole("y")],
+        );
+
+        // `c` has children `b` and `d`
+        let tree = test_tree();
+        let node_c = &tree.collect_pre_order()[4];
+        let bindings = node_c.match_template(&template, node_eq).unwrap();
+        assert_eq!(bindings.len(), 2);
+        assert_eq!(bindings["x"], leaf("b"));
+        assert_eq!(bindings["y"].data, "d");
+
+        // the node itself, the number of children and repeated holes must match
+        assert!(tree.match_template(&template, node_eq).is_none());
+        assert!(leaf("c").match_template(&template, node_eq).is_none());
+        let same_children = Template::node(
+            TestTreeNode::new(vec![leaf("_"), leaf("_")], "c".to_string()),
+            vec![Template::hole("x"), Template::hole("x")],
+        );
+        assert!(node_c.match_template(&same_children, node_eq).is_none());
+        let c_with_same_children =
+            TestTreeNode::new(vec![leaf("b"), leaf("b")], "c".to_string());
+        assert!(c_with_same_children
+            .match_template(&same_children, node_eq)
+            .is_some());
+
+        // swap the children
+        let swapped = Template::node(
+            TestTreeNode::new(vec![leaf("_"), leaf("_")], "c".to_string()),
+            vec![Template::hole("y"), Template::hole("x")],
+        );
+        let instantiated = TestTreeNode::instantiate(swapped, &bindings)?;
+        assert_eq!(
+            instantiated,
+            TestTreeNode::new(vec![bindings["y"].clone(), leaf("b")], "c".to_string())
+        );
+
+        assert!(TestTreeNode::instantiate(Template::hole("z"), &bindings)
+            .unwrap_err()
+            .strip_backtrace()
+            .starts_with("Internal error: Template hole z is not bound"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_map_nodes_until_stop() -> Result<()> {
+        let result = map_nodes_until_stop(vec![1, 2, 3, 4, 5], |i| {
+            Ok(if i == 3 {
+                Transformed::new(i * 10, true, TreeNodeRecursion::Stop)
+            } else {
+                Transformed::yes(i * 10)
+            })
+        })?;
+        assert_eq!(
+            result,
+            Transformed::new(vec![10, 20, 30, 4, 5], true, TreeNodeRecursion::Stop)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_lazy_node() -> Result<()> {
+        let calls = Rc::new(Cell::new(0));
+        let lazy_tree = || {
+            let calls = Rc::clone(&calls);
+            LazyNode::new("root", move || {
+                calls.set(calls.get() + 1);
+                vec![
+                    LazyNode::new_materialized("a", vec![]),
+                    LazyNode::new_materialized("b", vec![]),
+                ]
+            })
+        };
+
+        // the children are not built if the traversal doesn't visit them
+        let tree = lazy_tree();
+        tree.apply(|_| Ok(TreeNodeRecursion::Jump))?;
+        assert!(!tree.is_materialized());
+        assert_eq!(calls.get(), 0);
+
+        for _ in 0..3 {
+            let mut visits = vec![];
+            tree.apply(|n| {
+                visits.push(n.data);
+                Ok(TreeNodeRecursion::Continue)
+            })?;
+            assert_eq!(visits, vec!["root", "a", "b"]);
+        }
+        assert!(tree.is_materialized());
+        assert_eq!(calls.get(), 1);
+
+        let transformed = lazy_tree().transform_up(|mut n| {
+            n.data = if n.data == "a" { "c" } else { n.data };
+            Ok(Transformed::yes(n))
+        })?;
+        assert!(transformed.transformed);
+        let children = transformed
+            .data
+            .children()
+            .iter()
+            .map(|n| n.data)
+            .collect::<Vec<_>>();
+        assert_eq!(children, vec!["c", "b"]);
+        assert_eq!(calls.get(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_transform_up_with_rebuild_counts() -> Result<()> {
+        let is_leaf = |n: &TestTreeNode<String>| n.children.is_empty();
+
+        let (transformed, counts) = test_tree()
+            .transform_up_with_rebuild_counts(&is_leaf, |n| Ok(Transformed::no(n)))?;
+        assert!(!transformed.transformed);
+        assert!(counts.is_empty());
+
+        let (transformed, counts) = test_tree()
+            .transform_up_with_rebuild_counts(&is_leaf, transform_yes("f_up"))?;
+        assert_eq!(transformed, Transformed::yes(transformed_up_tree()));
+        assert_eq!(counts, HashMap::from([(true, 3), (false, 7)]));
+
+        // only `a` and its ancestors are rebuilt
+        let (_, counts) =
+            test_tree().transform_up_with_rebuild_counts(&is_leaf, |n| {
+                if n.data == "a" {
+                    transform_yes("f_up")(n)
+                } else {
+                    Ok(Transformed::no(n))
+                }
+            })?;
+        assert_eq!(counts, HashMap::from([(true, 1), (false, 6)]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_bounded_rewriter() -> Result<()> {
+        let rewriter = TestRewriter::new(
+            Box::new(|n| Ok(Transformed::no(n))),
+            Box::new(transform_yes("f_up")),
+        );
+        let mut rewriter = BoundedRewriter::new(rewriter, 3);
+        let rewritten = test_tree().rewrite(&mut rewriter)?;
+        assert!(rewritten.transformed);
+        assert_eq!(rewritten.tnr, TreeNodeRecursion::Stop);
+        assert_eq!(rewriter.remaining(), 0);
+
+        let changed = rewritten
+            .data
+            .collect_pre_order()
+            .into_iter()
+            .filter(|n| n.data.starts_with("f_up"))
+            .map(|n| n.data.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(changed, vec!["f_up(b)", "f_up(d)", "f_up(a)"]);
+
+        // the budget is not used up if there are fewer changes
+        let rewriter = TestRewriter::new(
+            Box::new(|n| Ok(Transformed::no(n))),
+            Box::new(transform_yes("f_up")),
+        );
+        let mut rewriter = BoundedRewriter::new(rewriter, 20);
+        let rewritten = test_tree().rewrite(&mut rewriter)?;
+        assert_eq!(rewritten, Transformed::yes(transformed_up_tree()));
+        assert_eq!(rewriter.remaining(), 10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_transformed_constructors() {
+        assert_eq!(
+            Transformed::yes_and_stop(1),
+            Transformed::new(1, true, TreeNodeRecursion::Stop)
+        );
+        assert_eq!(
+            Transformed::yes_and_jump(1),
+            Transformed::new(1, true, TreeNodeRecursion::Jump)
+        );
+        assert_eq!(
+            Transformed::no_and_stop(1),
+            Transformed::new(1, false, TreeNodeRecursion::Stop)
+        );
+    }
+
+    #[test]
+    fn test_apply_until() -> Result<()> {
+        let tree = test_tree();
+        let mut visited = vec![];
+        let count = tree.apply_until(0, &mut |count, n| {
+            visited.push(n.data.clone());
+            let count = count + 1;
+            let tnr = if count == 3 {
+                TreeNodeRecursion::Stop
+            } else {
+                TreeNodeRecursion::Continue
+            };
+            Ok((count, tnr))
+        })?;
+        assert_eq!(count, 3);
+        assert_eq!(visited, vec!["j", "i", "f"]);
+
+        let count = tree.apply_until(0, &mut |count, _| {
+            Ok((count + 1, TreeNodeRecursion::Continue))
+        })?;
+        assert_eq!(count, 10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_structurally_eq() {
+        let tree = test_tree();
+        let same_structure = transformed_up_tree();
+        assert!(tree.structurally_eq(&same_structure, |_, _| true));
+        assert!(!tree.structurally_eq(&same_structure, |n, o| n.data == o.data));
+        assert!(tree.structurally_eq(&test_tree(), |n, o| n.data == o.data));
+
+        let other_structure = TestTreeNode::new(
+            vec![TestTreeNode::new(vec![], "i".to_string())],
+            "j".to_string(),
+        );
+        assert!(!tree.structurally_eq(&other_structure, |_, _| true));
+        assert!(!other_structure.structurally_eq(&tree, |_, _| true));
+    }
+
+    #[test]
+    fn test_make_and_apply_patch() -> Result<()> {
+        let tree = test_tree();
+        let node_eq =
+            |n: &TestTreeNode<String>, o: &TestTreeNode<String>| n.data == o.data;
+        let optimized = test_tree()
+            .transform_down(|n| {
+                Ok(match n.data.as_str() {
+                    // replace the subtree of `d`
+                    "d" => Transformed::yes(TestTreeNode::new(vec![], "x".to_string())),
+                    "h" => {
+                        Transformed::yes(TestTreeNode::new(n.children, "y".to_string()))
+                    }
+                    _ => Transformed::no(n),
+                })
+            })?
+            .data;
+
+        let patch = tree.make_patch(&optimized, node_eq)?;
+        let paths = patch
+            .entries()
+            .iter()
+            .map(|(path, replacement)| (path.clone(), replacement.data.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![(vec![0, 0, 0, 0, 1], "x"), (vec![0, 0, 1, 0], "y")]
+        );
+        assert_eq!(test_tree().apply_patch(&patch)?, optimized);
+
+        let patch = tree.make_patch(&test_tree(), node_eq)?;
+        assert!(patch.is_empty());
+        assert_eq!(test_tree().apply_patch(&patch)?, tree);
+
+        let leaf = TestTreeNode::new(vec![], "a".to_string());
+        assert!(leaf
+            .apply_patch(&tree.make_patch(&optimized, node_eq)?)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_child_count() {
+        let tree = test_tree();
+        // `f` has children `e` and `g`
+        let node_f = tree.collect_pre_order()[2];
+        assert_eq!(node_f.data, "f");
+        assert_eq!(node_f.child_count(), 2);
+        assert_eq!(tree.child_count(), 1);
+        assert_eq!(TestTreeNode::new(vec![], "a".to_string()).child_count(), 0);
+    }
+
+    #[test]
+    fn test_try_fold() -> Result<()> {
+        let tree = transformed_up_tree();
+        let len =
+            tree.try_fold(0, |len, n| Ok(ControlFlow::Continue(len + n.data.len())))?;
+        assert_eq!(len, 70);
+
+        let tree = test_tree();
+        let visited = tree.try_fold(String::new(), |mut visited, n| {
+            visited.push_str(&n.data);
+            Ok(if n.data == "c" {
+                ControlFlow::Break(visited)
+            } else {
+                ControlFlow::Continue(visited)
+            })
+        })?;
+        assert_eq!(visited, "jifec");
+
+        let result = tree.try_fold(0, |count, n| {
+            if n.data == "e" {
+                Err(DataFusionError::Execution("fold failed at e".to_string()))
+            } else {
+                Ok(ControlFlow::Continue(count + 1))
+            }
+        });
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_transform_up_shared() -> Result<()> {
+        let subtree = || {
+            TestTreeNode::new(
+                vec![TestTreeNode::new(vec![], "b".to_string())],
+                "a".to_string(),
+            )
+        };
+        let tree = TestTreeNode::new(
+            vec![
+                subtree(),
+                TestTreeNode::new(vec![], "c".to_string()),
+                subtree(),
+            ],
+            "r".to_string(),
+        );
+
+        let mut calls = vec![];
+        let mut f = |n: TestTreeNode<String>| {
+            calls.push(n.data.clone());
+            transform_yes("f_up")(n)
+        };
+        let transformed = tree.transform_up_shared(&mut f)?;
+
+        let transformed_subtree = || {
+            TestTreeNode::new(
+                vec![TestTreeNode::new(vec![], "f_up(b)".to_string())],
+                "f_up(a)".to_string(),
+            )
+        };
+        let expected = TestTreeNode::new(
+            vec![
+                transformed_subtree(),
+                TestTreeNode::new(vec![], "f_up(c)".to_string()),
+                transformed_subtree(),
+            ],
+            "f_up(r)".to_string(),
+        );
+        assert_eq!(transformed, Transformed::yes(expected));
+        assert_eq!(calls, vec!["b", "a", "c", "r"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_map_children_indexed() -> Result<()> {
+        fn append_index(
+            node: TestTreeNode<String>,
+        ) -> Result<Transformed<TestTreeNode<String>>> {
+            node.map_children_indexed(|i, c| {
+                let mut c = append_index(c)?.data;
+                c.data = format!("{}{i}", c.data);
+                Ok(Transformed::yes(c))
+            })
+        }
+
+        let transformed = append_index(test_tree())?;
+        assert!(transformed.transformed);
+        let nodes = transformed
+            .data
+            .collect_pre_order()
+            .into_iter()
+            .map(|n| n.data.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            nodes,
+            vec!["j", "i0", "f0", "e0", "c0", "b0", "d1", "a0", "g1", "h0"]
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_apply_many_parallel() -> Result<()> {
+        use crate::tree_node::apply_many_parallel;
+
+        #[derive(Default)]
+        struct CountingVisitor {
+            count: usize,
+        }
+
+        impl<'n> TreeNodeVisitor<'n> for CountingVisitor {
+            type Node = TestTreeNode<String>;
+
+            fn f_down(&mut self, _node: &'n Self::Node) -> Result<TreeNodeRecursion> {
+                self.count += 1;
+                Ok(TreeNodeRecursion::Continue)
+            }
+        }
+
+        let trees = (0..1000).map(|_| test_tree()).collect::<Vec<_>>();
+
+        let mut serial = CountingVisitor::default();
+        for tree in &trees {
+            tree.visit(&mut serial)?;
+        }
+
+        let parallel = apply_many_parallel(&trees, CountingVisitor::default, |a, b| {
+            CountingVisitor {
+                count: a.count + b.count,
+            }
+        })?;
+        assert_eq!(parallel.count, 10 * 1000);
+        assert_eq!(parallel.count, serial.count);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_indented() {
+        let rendered = test_tree().format_indented(|n| n.data.clone());
+        let expected = "\
+j
+  i
+    f
+      e
+        c
+          b
+          d
+            a
+      g
+        h
+";
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn test_with_tnr_and_map_tnr() {
+        let t = Transformed::yes(1).with_tnr(TreeNodeRecursion::Jump);
+        assert_eq!(t, Transformed::new(1, true, TreeNodeRecursion::Jump));
+
+        let t = Transformed::no(2).with_tnr(TreeNodeRecursion::Stop);
+        assert_eq!(t, Transformed::new(2, false, TreeNodeRecursion::Stop));
+
+        let t = Transformed::yes_and_jump(3).map_tnr(|tnr| {
+            assert_eq!(tnr, TreeNodeRecursion::Jump);
+            TreeNodeRecursion::Continue
+        });
+        assert_eq!(t, Transformed::new(3, true, TreeNodeRecursion::Continue));
+
+        let t = Transformed::no(4).map_tnr(|tnr| tnr.then(TreeNodeRecursion::Stop));
+        assert_eq!(t, Transformed::new(4, false, TreeNodeRecursion::Stop));
+    }
+
+    #[test]
+    fn test_zip_apply() -> Result<()> {
+        let tree = test_tree();
+        let other = transformed_up_tree();
+        let mut pairs = vec![];
+        let tnr = tree.zip_apply(&other, |n, o| {
+            pairs.push(format!("{}={}", n.data, o.data));
+            Ok(TreeNodeRecursion::Continue)
+        })?;
+        assert_eq!(tnr, TreeNodeRecursion::Continue);
+        assert_eq!(
+            pairs,
+            ["j", "i", "f", "e", "c", "b", "d", "a", "g", "h"]
+                .into_iter()
+                .map(|n| format!("{n}=f_up({n})"))
+                .collect::<Vec<_>>()
+        );
+
+        let mismatched = TestTreeNode::new(
+            vec![TestTreeNode::new(vec![], "i".to_string())],
+            "j".to_string(),
+        );
+        let err = tree
+            .zip_apply(&mismatched, |_, _| Ok(TreeNodeRecursion::Continue))
+            .unwrap_err();
+        assert!(err
+            .strip_backtrace()
+            .starts_with("Internal error: Trees diverge: nodes with 1 and 0 children"));
+
+        // The divergence is not reached if the traversal jumps over it
+        let tnr = tree.zip_apply(&mismatched, |n, _| {
+            Ok(if n.data == "i" {
+                TreeNodeRecursion::Jump
+            } else {
+                TreeNodeRecursion::Continue
+            })
+        })?;
+        assert_eq!(tnr, TreeNodeRecursion::Continue);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewrite_recorder() -> Result<()> {
+        let rename_a = TestRewriter::new(
+            Box::new(|n| Ok(Transformed::no(n))),
+            Box::new(|n: TestTreeNode<String>| {
+                Ok(if n.data == "a" {
+                    Transformed::yes(TestTreeNode::new(n.children, "x".to_string()))
+                } else {
+                    Transformed::no(n)
+                })
+            }),
+        );
+        let mut recorder = RewriteRecorder::new(rename_a);
+        let rewritten = test_tree().rewrite(&mut recorder)?;
+        assert!(rewritten.transformed);
+        assert_eq!(recorder.changed_paths(), [vec![0, 0, 0, 0, 1, 0]]);
+
+        // Jumping from `f_up` of `a` bypasses the `f_up` of `d`, `c` and `e`, but
+        // the path of `h` is still tracked correctly
+        let rename_a_and_h = TestRewriter::new(
+            Box::new(|n| Ok(Transformed::no(n))),
+            Box::new(|n: TestTreeNode<String>| {
+                Ok(match n.data.as_str() {
+                    "a" => Transformed::new(
+                        TestTreeNode::new(n.children, "x".to_string()),
+                        true,
+                        TreeNodeRecursion::Jump,
+                    ),
+                    "h" => {
+                        Transformed::yes(TestTreeNode::new(n.children, "y".to_string()))
+                    }
+                    _ => Transformed::no(n),
+                })
+            }),
+        );
+        let mut recorder = RewriteRecorder::new(rename_a_and_h);
+        test_tree().rewrite(&mut recorder)?;
+        assert_eq!(
+            recorder.changed_paths(),
+            [vec![0, 0, 0, 0, 1, 0], vec![0, 0, 1, 0]]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_zip_visitor() -> Result<()> {
+        struct CountingVisitor {
+            stop_at: Option<&'static str>,
+            down: usize,
+            up: usize,
+        }
+
+        impl CountingVisitor {
+            fn new(stop_at: Option<&'static str>) -> Self {
+                Self {
+                    stop_at,
+                    down: 0,
+                    up: 0,
+                }
+            }
+        }
+
+        impl<'n> TreeNodeVisitor<'n> for CountingVisitor {
+            type Node = TestTreeNode<String>;
+
+            fn f_down(&mut self, node: &'n Self::Node) -> Result<TreeNodeRecursion> {
+                self.down += 1;
+                Ok(if self.stop_at == Some(node.data.as_str()) {
+                    TreeNodeRecursion::Stop
+                } else {
+                    TreeNodeRecursion::Continue
+                })
+            }
+
+            fn f_up(&mut self, _node: &'n Self::Node) -> Result<TreeNodeRecursion> {
+                self.up += 1;
+                Ok(TreeNodeRecursion::Continue)
+            }
+        }
+
+        let tree = test_tree();
+        let mut visitor = CountingVisitor::new(None).zip(CountingVisitor::new(None));
+        assert_eq!(tree.visit(&mut visitor)?, TreeNodeRecursion::Continue);
+        let (first, second) = visitor.into_inner();
+        assert_eq!((first.down, first.up), (10, 10));
+        assert_eq!((second.down, second.up), (10, 10));
+
+        // A `Stop` from the second visitor stops the traversal for both
+        let mut visitor = CountingVisitor::new(None).zip(CountingVisitor::new(Some("c")));
+        assert_eq!(tree.visit(&mut visitor)?, TreeNodeRecursion::Stop);
+        let (first, second) = visitor.into_inner();
+        assert_eq!((first.down, first.up), (5, 0));
+        assert_eq!((second.down, second.up), (5, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_transformed_zip() {
+        use TreeNodeRecursion::*;
+
+        let cases = [
+            (Continue, Continue, Continue),
+            (Continue, Jump, Jump),
+            (Continue, Stop, Stop),
+            (Jump, Continue, Jump),
+            (Jump, Jump, Jump),
+            (Jump, Stop, Stop),
+            (Stop, Continue, Stop),
+            (Stop, Jump, Stop),
+            (Stop, Stop, Stop),
+        ];
+        for (tnr, other_tnr, expected_tnr) in cases {
+            for (transformed, other_transformed) in
+                [(false, false), (false, true), (true, false), (true, true)]
+            {
+                let t = Transformed::new(1, transformed, tnr);
+                let other = Transformed::new("a", other_transformed, other_tnr);
+                assert_eq!(
+                    t.zip(other),
+                    Transformed::new(
+                        (1, "a"),
+                        transformed || other_transformed,
+                        expected_tnr
+                    )
+                );
+            }
+        }
+    }
+
+    fn arc_test_tree() -> Arc<DynTestNode> {
+        let node_a = DynTestNode::new(vec![], "a");
+        let node_b = DynTestNode::new(vec![], "b");
+        let node_d = DynTestNode::new(vec![node_a], "d");
+        let node_c = DynTestNode::new(vec![node_b, node_d], "c");
+        let node_e = DynTestNode::new(vec![node_c], "e");
+        let node_h = DynTestNode::new(vec![], "h");
+        let node_g = DynTestNode::new(vec![node_h], "g");
+        let node_f = DynTestNode::new(vec![node_e, node_g], "f");
+        let node_i = DynTestNode::new(vec![node_f], "i");
+        DynTestNode::new(vec![node_i], "j")
+    }
+
+    #[test]
+    fn test_dyn_tree_node_transform_up() -> Result<()> {
+        type F = fn(Arc<DynTestNode>) -> Result<Transformed<Arc<DynTestNode>>>;
+
+        let fs: Vec<F> = vec![
+            |n| Ok(Transformed::no(n)),
+            |n| {
+                let data = format!("f_up({})", n.data);
+                Ok(Transformed::yes(DynTestNode::new(
+                    n.children.clone(),
+                    &data,
+                )))
+            },
+            |n| match n.data.as_str() {
+                "d" => Ok(Transformed::new(n, false, TreeNodeRecursion::Jump)),
+                "h" => Ok(Transformed::yes(DynTestNode::new(n.children.clone(), "x"))),
+                _ => Ok(Transformed::no(n)),
+            },
+            |n| match n.data.as_str() {
+                "b" => Ok(Transformed::new(
+                    DynTestNode::new(n.children.clone(), "x"),
+                    true,
+                    TreeNodeRecursion::Stop,
+                )),
+                _ => Ok(Transformed::yes(DynTestNode::new(n.children.clone(), "y"))),
+            },
+        ];
+        for f in fs {
+            // `transform_down_up` still uses the recursive implementation
+            let expected =
+                arc_test_tree().transform_down_up(|n| Ok(Transformed::no(n)), f)?;
+            let tree = arc_test_tree();
+            let transformed = Arc::clone(&tree).transform_up(f)?;
+            assert_eq!(transformed, expected);
+            if !transformed.transformed {
+                assert!(Arc::ptr_eq(&transformed.data, &tree));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_dyn_tree_node_transform_up_deep() -> Result<()> {
+        let depth = 200_000;
+        let mut tree = DynTestNode::new(vec![], "0");
+        for i in 1..depth {
+            tree = DynTestNode::new(vec![tree], &i.to_string());
+        }
+
+        let transformed = tree.transform_up(|n| {
+            let data = n.data.parse::<usize>().unwrap() + 1;
+            Ok(Transformed::yes(DynTestNode::new(
+                n.children.clone(),
+                &data.to_string(),
+            )))
+        })?;
+        assert!(transformed.transformed);
+
+        let mut node = &transformed.data;
+        for i in (1..=depth).rev() {
+            assert_eq!(node.data, i.to_string());
+            match node.children.as_slice() {
+                [child] => node = child,
+                [] => assert_eq!(i, 1),
+                _ => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct ConcreteTestNode {
+        data: String,
+        children: Vec<ConcreteTestNode>,
+    }
+
+    impl From<TestTreeNode<String>> for ConcreteTestNode {
+        fn from(node: TestTreeNode<String>) -> Self {
+            Self {
+                data: node.data,
+                children: node.children.into_iter().map(Self::from).collect(),
+            }
+        }
+    }
+
+    impl ConcreteTreeNode for ConcreteTestNode {
+        fn children(&self) -> &[Self] {
+            &self.children
+        }
+
+        fn take_children(mut self) -> (Self, Vec<Self>) {
+            let children = std::mem::take(&mut self.children);
+            (self, children)
+        }
+
+        fn with_new_children(mut self, children: Vec<Self>) -> Result<Self> {
+            self.children = children;
+            Ok(self)
+        }
+    }
+
+    #[test]
+    fn test_filter_children() -> Result<()> {
+        let tree = ConcreteTestNode::from(test_tree());
+        let filtered = tree.filter_children(|n| !n.children.is_empty())?;
+        assert!(filtered.transformed);
+        let nodes = filtered
+            .data
+            .collect_pre_order()
+            .into_iter()
+            .map(|n| n.data.as_str())
+            .collect::<Vec<_>>();
+        // Only the original leaves are removed, `d` and `g` become leaves
+        assert_eq!(nodes, vec!["j", "i", "f", "e", "c", "d", "g"]);
+
+        let tree = ConcreteTestNode::from(test_tree());
+        let unchanged = tree.filter_children(|_| true)?;
+        assert!(!unchanged.transformed);
+        assert_eq!(unchanged.data, ConcreteTestNode::from(test_tree()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_memoized() -> Result<()> {
+        let shared = DynTestNode::new(vec![DynTestNode::new(vec![], "x")], "s");
+        let tree = DynTestNode::new(
+            vec![
+                Arc::clone(&shared),
+                DynTestNode::new(vec![Arc::clone(&shared)], "m"),
+            ],
+            "r",
+        );
+
+        let mut cache = Memoize::new();
+        let mut visited = vec![];
+        tree.apply_memoized(&mut cache, |node| {
+            visited.push(node.data.clone());
+            Ok(node.children.len())
+        })?;
+        // `s` and `x` occur twice but are analyzed once
+        assert_eq!(visited, vec!["r", "s", "x", "m"]);
+        assert_eq!(cache.len(), 4);
+        assert_eq!(cache.get(&shared), Some(&1));
+        assert_eq!(cache.get(&tree), Some(&2));
+
+        // Cached nodes are not analyzed again
+        let mut visited = vec![];
+        let other = DynTestNode::new(vec![Arc::clone(&shared)], "o");
+        other.apply_memoized(&mut cache, |node| {
+            visited.push(node.data.clone());
+            Ok(node.children.len())
+        })?;
+        assert_eq!(visited, vec!["o"]);
+        assert_eq!(cache.len(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_node_recursion_predicates() {
+        use TreeNodeRecursion::*;
+
+        let cases = [
+            (Continue, true, false, false),
+            (Jump, false, true, false),
+            (Stop, false, false, true),
+        ];
+        for (tnr, is_continue, is_jump, is_stop) in cases {
+            assert_eq!(tnr.is_continue(), is_continue);
+            assert_eq!(tnr.is_jump(), is_jump);
+            assert_eq!(tnr.is_stop(), is_stop);
+        }
+    }
+
+    #[test]
+    fn test_apply_to_depth() -> Result<()> {
+        let tree = test_tree();
+        let mut visited = vec![];
+        let tnr = tree.apply_to_depth(2, |n| {
+            visited.push(n.data.clone());
+            Ok(TreeNodeRecursion::Continue)
+        })?;
+        assert_eq!(tnr, TreeNodeRecursion::Continue);
+        assert_eq!(visited, vec!["j", "i", "f"]);
+
+        let mut visited = vec![];
+        tree.apply_to_depth(0, |n| {
+            visited.push(n.data.clone());
+            Ok(TreeNodeRecursion::Continue)
+        })?;
+        assert_eq!(visited, vec!["j"]);
+
+        // A big enough depth visits the whole tree
+        let mut visited = vec![];
+        tree.apply_to_depth(usize::MAX, |n| {
+            visited.push(n.data.clone());
+            Ok(TreeNodeRecursion::Continue)
+        })?;
+        assert_eq!(visited.len(), 10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_subtrees() {
+        let leaf = |data: &str| TestTreeNode::new(vec![], data.to_string());
+        let a_plus_b = || TestTreeNode::new(vec![leaf("a"), leaf("b")], "+".to_string());
+        let tree = TestTreeNode::new(
+            vec![
+                a_plus_b(),
+                TestTreeNode::new(vec![a_plus_b(), leaf("c")], "*".to_string()),
+            ],
+            "-".to_string(),
+        );
+
+        let node_key = |n: &TestTreeNode<String>| n.data.clone();
+        let duplicates = tree.duplicate_subtrees(node_key);
+        assert_eq!(duplicates, vec![&leaf("a"), &leaf("b"), &a_plus_b()]);
+        assert_eq!(duplicates.iter().filter(|d| ***d == a_plus_b()).count(), 1);
+
+        // nodes with equal keys but different children are not duplicates
+        let tree = TestTreeNode::new(
+            vec![
+                a_plus_b(),
+                TestTreeNode::new(vec![leaf("b"), leaf("a")], "+".to_string()),
+            ],
+            "-".to_string(),
+        );
+        assert_eq!(
+            tree.duplicate_subtrees(node_key),
+            vec![&leaf("b"), &leaf("a")]
+        );
+
+        assert!(test_tree().duplicate_subtrees(node_key).is_empty());
+    }
+
+    #[test]
+    fn test_rewrite_should_visit() -> Result<()> {
+        #[derive(Default)]
+        struct LeafRewriter {
+            calls: Vec<String>,
+        }
+
+        impl TreeNodeRewriter for LeafRewriter {
+            type Node = TestTreeNode<String>;
+
+            fn f_down(&mut self, node: Self::Node) -> Result<Transformed<Self::Node>> {
+                self.calls.push(format!("f_down({})", node.data));
+                Ok(Transformed::no(node))
+            }
+
+            fn f_up(&mut self, node: Self::Node) -> Result<Transformed<Self::Node>> {
+                self.calls.push(format!("f_up({})", node.data));
+                Ok(Transformed::yes(TestTreeNode::new(
+                    node.children,
+                    format!("leaf({})", node.data),
+                )))
+            }
+
+            fn should_visit(&self, node: &Self::Node) -> bool {
+                node.children.is_empty()
+            }
+        }
+
+        let mut rewriter = LeafRewriter::default();
+        let rewritten = test_tree().rewrite(&mut rewriter)?;
+        assert!(rewritten.transformed);
+        assert_eq!(
+            rewriter.calls,
+            vec![
+                "f_down(b)",
+                "f_up(b)",
+                "f_down(a)",
+                "f_up(a)",
+                "f_down(h)",
+                "f_up(h)"
+            ]
+        );
+        let nodes = rewritten
+            .data
+            .collect_pre_order()
+            .into_iter()
+            .map(|n| n.data.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            nodes,
+            vec!["j", "i", "f", "e", "c", "leaf(b)", "d", "leaf(a)", "g", "leaf(h)"]
+        );
+
+        // Adapters honor the `should_visit` of the inner rewriter
+        let mut recorder = RewriteRecorder::new(LeafRewriter::default());
+        test_tree().rewrite(&mut recorder)?;
+        assert_eq!(recorder.inner().calls, rewriter.calls);
+        assert_eq!(
+            recorder.changed_paths(),
+            [
+                vec![0, 0, 0, 0, 0],
+                vec![0, 0, 0, 0, 1, 0],
+                vec![0, 0, 1, 0]
+            ]
+        );
+        let mut bounded = BoundedRewriter::new(LeafRewriter::default(), 10);
+        test_tree().rewrite(&mut bounded)?;
+        assert_eq!(bounded.inner().calls, rewriter.calls);
+        assert_eq!(bounded.remaining(), 7);
+        Ok(())
+    }
+
+    #[cfg(feature = "smallvec")]
+    #[test]
+    fn test_map_until_stop_and_collect_small() -> Result<()> {
+        fn f(i: usize) -> Result<Transformed<usize>> {
+            Ok(match i {
+                1 => Transformed::no(i),
+                3 => Transformed::new(i * 10, true, TreeNodeRecursion::Stop),
+                _ => Transformed::new(i * 10, true, TreeNodeRecursion::Jump),
+            })
+        }
+
+        for len in [1, 2, 5] {
+            let vec = (0..len).map_until_stop_and_collect(f)?;
+            let small = (0..len).map_until_stop_and_collect_small::<2, _>(f)?;
+            assert_eq!(small.data.spilled(), len > 2);
+            assert_eq!(
+                Transformed::new(small.data.into_vec(), small.transformed, small.tnr),
+                vec
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_children_iter() -> Result<()> {
+        #[derive(Debug, PartialEq)]
+        struct IterTestNode {
+            data: String,
+            children: Vec<IterTestNode>,
+        }
+
+        impl TreeNode for IterTestNode {
+            fn apply_children<'n, F: FnMut(&'n Self) -> Result<TreeNodeRecursion>>(
+                &'n self,
+                f: F,
+            ) -> Result<TreeNodeRecursion> {
+                self.children_iter().apply_until_stop(f)
+            }
+
+            fn children_iter(&self) -> impl Iterator<Item = &Self> {
+                self.children.iter()
+            }
+
+            fn map_children<F: FnMut(Self) -> Result<Transformed<Self>>>(
+                self,
+                f: F,
+            ) -> Result<Transformed<Self>> {
+                Ok(self
+                    .children
+                    .into_iter()
+                    .map_until_stop_and_collect(f)?
+                    .update_data(|children| Self {
+                        data: self.data,
+                        children,
+                    }))
+            }
+        }
+
+        fn from_test_tree(node: TestTreeNode<String>) -> IterTestNode {
+            IterTestNode {
+                data: node.data,
+                children: node.children.into_iter().map(from_test_tree).collect(),
+            }
+        }
+
+        // `apply_children` is implemented with `children_iter`
+        let tree = from_test_tree(test_tree());
+        let mut visited = vec![];
+        tree.apply(|n| {
+            visited.push(n.data.as_str());
+            Ok(if n.data == "c" {
+                TreeNodeRecursion::Jump
+            } else {
+                TreeNodeRecursion::Continue
+            })
+        })?;
+        assert_eq!(visited, vec!["j", "i", "f", "e", "c", "g", "h"]);
+
+        // The default `children_iter` relies on `apply_children`
+        let tree = test_tree();
+        let children = tree.children_iter().collect::<Vec<_>>();
+        assert_eq!(children, vec![&tree.children[0]]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_transformed_unzip() {
+        for tnr in [
+            TreeNodeRecursion::Continue,
+            TreeNodeRecursion::Jump,
+            TreeNodeRecursion::Stop,
+        ] {
+            for transformed in [false, true] {
+                let (u, v) = Transformed::new((1, "a"), transformed, tnr).unzip();
+                assert_eq!(u, Transformed::new(1, transformed, tnr));
+                assert_eq!(v, Transformed::new("a", transformed, tnr));
+            }
+        }
+
+        let t = Transformed::yes(1).zip(Transformed::no("a"));
+        assert_eq!(t.unzip(), (Transformed::yes(1), Transformed::yes("a")));
+    }
+
+    #[test]
+    fn test_apply_leaves() -> Result<()> {
+        let tree = test_tree();
+        let mut visited = vec![];
+        let tnr = tree.apply_leaves(|n| {
+            visited.push(n.data.clone());
+            Ok(TreeNodeRecursion::Continue)
+        })?;
+        assert_eq!(tnr, TreeNodeRecursion::Continue);
+        assert_eq!(visited, vec!["b", "a", "h"]);
+
+        let mut visited = vec![];
+        let tnr = tree.apply_leaves(|n| {
+            visited.push(n.data.clone());
+            Ok(if n.data == "a" {
+                TreeNodeRecursion::Stop
+            } else {
+                TreeNodeRecursion::Continue
+            })
+        })?;
+        assert_eq!(tnr, TreeNodeRecursion::Stop);
+        assert_eq!(visited, vec!["b", "a"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewrite_flat() -> Result<()> {
+        struct DuplicateD;
+
+        impl TreeNodeRewriter for DuplicateD {
+            type Node = ConcreteTestNode;
+
+            fn f_up_expand(
+                &mut self,
+                node: Self::Node,
+            ) -> Result<Transformed<Vec<Self::Node>>> {
+                Ok(if node.data == "d" {
+                    let copy = ConcreteTestNode {
+                        data: "d".to_string(),
+                        children: vec![ConcreteTestNode::from(TestTreeNode::new(
+                            vec![],
+                            "a".to_string(),
+                        ))],
+                    };
+                    Transformed::yes(vec![node, copy])
+                } else {
+                    Transformed::no(vec![node])
+                })
+            }
+        }
+
+        let tree = ConcreteTestNode::from(test_tree());
+        let rewritten = tree.rewrite_flat(&mut DuplicateD)?;
+        assert!(rewritten.transformed);
+        let nodes = rewritten
+            .data
+            .collect_pre_order()
+            .into_iter()
+            .map(|n| n.data.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            nodes,
+            vec!["j", "i", "f", "e", "c", "b", "d", "a", "d", "a", "g", "h"]
+        );
+
+        // The default `f_up_expand` calls `f_up`
+        struct Unchanged;
+
+        impl TreeNodeRewriter for Unchanged {
+            type Node = ConcreteTestNode;
+        }
+
+        let tree = ConcreteTestNode::from(test_tree());
+        let rewritten = tree.rewrite_flat(&mut Unchanged)?;
+        assert!(!rewritten.transformed);
+        assert_eq!(rewritten.data, ConcreteTestNode::from(test_tree()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_transform_down_up_with_context() -> Result<()> {
+        let fail_on_a = |n: TestTreeNode<String>| {
+            if n.data == "a" {
+                Err(DataFusionError::Execution("f_up failed".to_string()))
+            } else {
+                Ok(Transformed::no(n))
+            }
+        };
+
+        let err = test_tree()
+            .transform_down_up_with_context(|n| Ok(Transformed::no(n)), fail_on_a)
+            .unwrap_err();
+        assert_eq!(
+            err.strip_backtrace(),
+            "Error at depth 6 with child path [0, 0, 0, 0, 1, 0]\ncaused by\nExecution error: f_up failed"
+        );
+
+        // Without errors the result is the same as of `transform_down_up`
+        let transformed = test_tree().transform_down_up_with_context(
+            transform_yes("f_down"),
+            transform_yes("f_up"),
+        )?;
+        let expected = test_tree()
+            .transform_down_up(transform_yes("f_down"), transform_yes("f_up"))?;
+        assert_eq!(transformed, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_into_data_if_transformed() {
+        assert_eq!(Transformed::yes(1).into_data_if_transformed(), Some(1));
+        assert_eq!(
+            Transformed::yes_and_stop(2).into_data_if_transformed(),
+            Some(2)
+        );
+        assert_eq!(Transformed::no(3).into_data_if_transformed(), None);
+        assert_eq!(Transformed::no_and_stop(4).into_data_if_transformed(), None);
+    }
+
+    #[test]
+    fn test_apply_children_rev() -> Result<()> {
+        let tree = test_tree();
+        let node_f = tree.nodes_at_depth(2)[0];
+        assert_eq!(node_f.data, "f");
+
+        let mut visited = vec![];
+        let tnr = node_f.apply_children_rev(|c| {
+            visited.push(c.data.as_str());
+            Ok(TreeNodeRecursion::Continue)
+        })?;
+        assert_eq!(tnr, TreeNodeRecursion::Continue);
+        assert_eq!(visited, vec!["g", "e"]);
+
+        let mut visited = vec![];
+        let tnr = node_f.apply_children_rev(|c| {
+            visited.push(c.data.as_str());
+            Ok(TreeNodeRecursion::Stop)
+        })?;
+        assert_eq!(tnr, TreeNodeRecursion::Stop);
+        assert_eq!(visited, vec!["g"]);
+
+        let mut visited = vec![];
+        let tnr = node_f.apply_children_rev(|c| {
+            visited.push(c.data.as_str());
+            Ok(TreeNodeRecursion::Jump)
+        })?;
+        assert_eq!(tnr, TreeNodeRecursion::Jump);
+        assert_eq!(visited, vec!["g", "e"]);
+        Ok(())
+    }
 }
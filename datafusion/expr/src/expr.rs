@@ -25,11 +25,13 @@ use std::str::FromStr;
 use std::sync::Arc;
 
 use crate::expr_fn::binary_expr;
-use crate::logical_plan::Subquery;
-use crate::utils::expr_to_columns;
+use crate::logical_plan::{Filter, Subquery};
+use crate::utils::{
+    conjunction, expr_to_columns, split_conjunction, split_conjunction_owned,
+};
 use crate::{
-    built_in_window_function, udaf, BuiltInWindowFunction, ExprSchemable, Operator,
-    Signature, WindowFrame, WindowUDF,
+    built_in_window_function, udaf, BuiltInWindowFunction, ExprSchemable, LogicalPlan,
+    Operator, Signature, WindowFrame, WindowUDF,
 };
 use crate::{window_frame, Volatility};
 
@@ -38,7 +40,8 @@ use datafusion_common::tree_node::{
     Transformed, TransformedResult, TreeNode, TreeNodeRecursion,
 };
 use datafusion_common::{
-    plan_err, Column, DFSchema, Result, ScalarValue, TableReference,
+    internal_err, plan_err, Column, ColumnStatistics, DFSchema, ExprSchema, Result,
+    ScalarValue, TableReference,
 };
 use sqlparser::ast::{
     display_comma_separated, ExceptSelectItem, ExcludeSelectItem, IlikeSelectItem,
@@ -855,6 +858,20 @@ impl Exists {
     }
 }
 
+/// A correlated [`Exists`] subquery that can be converted into a semi join
+/// (or an anti join if negated) at the plan level.
+///
+/// See [`Expr::exists_to_semijoin_marker`] for more details.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct SemiJoinCandidate {
+    /// The subquery to join with
+    pub subquery: Subquery,
+    /// Whether the `EXISTS` is negated, i.e. an anti join is required
+    pub negated: bool,
+    /// The conjunction of all subquery predicates referencing outer columns
+    pub correlation: Expr,
+}
+
 /// User Defined Aggregate Function
 ///
 /// See [`udaf::AggregateUDF`] for more information.
@@ -1358,6 +1375,795 @@ impl Expr {
         .unwrap()
     }
 
+    /// Rewrites the plans of all subqueries that may appear in the expression,
+    /// such as `IN (SELECT ...)`, `EXISTS (SELECT ...)` or scalar subqueries,
+    /// using `f`.
+    ///
+    /// `f` is called with a [`LogicalPlan::Subquery`] and must return a
+    /// [`LogicalPlan::Subquery`]. Subqueries are visited in a top-down
+    /// (pre-order) traversal of the expression, but `f` is not applied to
+    /// subqueries embedded in the plans of other subqueries.
+    pub fn map_subqueries<F: FnMut(LogicalPlan) -> Result<Transformed<LogicalPlan>>>(
+        self,
+        mut f: F,
+    ) -> Result<Transformed<Expr>> {
+        self.transform_down(|expr| match expr {
+            Expr::Exists(Exists { subquery, negated }) => {
+                f(LogicalPlan::Subquery(subquery))?.map_data(|s| match s {
+                    LogicalPlan::Subquery(subquery) => {
+                        Ok(Expr::Exists(Exists { subquery, negated }))
+                    }
+                    _ => internal_err!("Transformation should return Subquery"),
+                })
+            }
+            Expr::InSubquery(InSubquery {
+                expr,
+                subquery,
+                negated,
+            }) => f(LogicalPlan::Subquery(subquery))?.map_data(|s| match s {
+                LogicalPlan::Subquery(subquery) => Ok(Expr::InSubquery(InSubquery {
+                    expr,
+                    subquery,
+                    negated,
+                })),
+                _ => internal_err!("Transformation should return Subquery"),
+            }),
+            Expr::ScalarSubquery(subquery) => f(LogicalPlan::Subquery(subquery))?
+                .map_data(|s| match s {
+                    LogicalPlan::Subquery(subquery) => Ok(Expr::ScalarSubquery(subquery)),
+                    _ => internal_err!("Transformation should return Subquery"),
+                }),
+            _ => Ok(Transformed::no(expr)),
+        })
+    }
+
+    /// Recursively flattens nested `coalesce` function calls into a single call.
+    ///
+    /// Arguments following the first non-null literal are dropped, as they can
+    /// never be the result of the call. Returns [`Transformed`] to signal if the
+    /// expression was changed.
+    ///
+    /// For example `coalesce(a, coalesce(b, c))` is flattened to
+    /// `coalesce(a, b, c)` and `coalesce(a, 1, b)` is truncated to
+    /// `coalesce(a, 1)`.
+    pub fn flatten_coalesce(self) -> Result<Transformed<Expr>> {
+        self.transform_up(|expr| match expr {
+            Expr::ScalarFunction(ScalarFunction { func, args })
+                if func.name() == "coalesce" =>
+            {
+                let mut transformed = false;
+                let mut new_args = Vec::with_capacity(args.len());
+                for arg in args {
+                    match arg {
+                        // nested calls are already flattened by the bottom-up traversal
+                        Expr::ScalarFunction(ScalarFunction { func, args })
+                            if func.name() == "coalesce" =>
+                        {
+                            new_args.extend(args);
+                            transformed = true;
+                        }
+                        _ => new_args.push(arg),
+                    }
+                }
+                if let Some(idx) = new_args
+                    .iter()
+                    .position(|arg| matches!(arg, Expr::Literal(v) if !v.is_null()))
+                {
+                    if idx + 1 < new_args.len() {
+                        new_args.truncate(idx + 1);
+                        transformed = true;
+                    }
+                }
+
+                Ok(Transformed::new_transformed(
+                    Expr::ScalarFunction(ScalarFunction::new_udf(func, new_args)),
+                    transformed,
+                ))
+            }
+            _ => Ok(Transformed::no(expr)),
+        })
+    }
+
+    /// Recursively flattens nested `concat` function calls into a single call
+    /// and folds adjacent string literal arguments into one literal.
+    ///
+    /// For example `concat(concat(a, b), c)` is flattened to `concat(a, b, c)`
+    /// and `concat('a', 'b', x)` is folded to `concat('ab', x)`.
+    pub fn flatten_concat(self) -> Result<Transformed<Expr>> {
+        self.transform_up(|expr| match expr {
+            Expr::ScalarFunction(ScalarFunction { func, args })
+                if func.name() == "concat" =>
+            {
+                let mut transformed = false;
+                let mut new_args: Vec<Expr> = Vec::with_capacity(args.len());
+                for arg in args {
+                    // nested calls are already flattened by the bottom-up traversal
+                    let arg_args = match arg {
+                        Expr::ScalarFunction(ScalarFunction { func, args })
+                            if func.name() == "concat" =>
+                        {
+                            transformed = true;
+                            args
+                        }
+                        _ => vec![arg],
+                    };
+                    for arg in arg_args {
+                        match (new_args.last_mut(), arg) {
+                            (
+                                Some(Expr::Literal(ScalarValue::Utf8(Some(prev)))),
+                                Expr::Literal(ScalarValue::Utf8(Some(s))),
+                            ) => {
+                                prev.push_str(&s);
+                                transformed = true;
+                            }
+                            (_, arg) => new_args.push(arg),
+                        }
+                    }
+                }
+
+                Ok(Transformed::new_transformed(
+                    Expr::ScalarFunction(ScalarFunction::new_udf(func, new_args)),
+                    transformed,
+                ))
+            }
+            _ => Ok(Transformed::no(expr)),
+        })
+    }
+
+    /// Returns a [`SemiJoinCandidate`] if this expression is a correlated
+    /// `EXISTS` subquery whose correlation is simple enough to be converted
+    /// into a semi join.
+    ///
+    /// The correlation is considered simple if outer references appear only in
+    /// `Filter` predicates of the subquery, every conjunct referencing outer
+    /// columns is free of disjunctions (`OR`), and the path from the subquery
+    /// root down to each such `Filter` consists of only `Projection`, `Filter`,
+    /// `SubqueryAlias` and `Sort` (without fetch) nodes. For example
+    /// `EXISTS (SELECT * FROM t2 WHERE t2.a = t1.a AND t2.b > 1)` is a
+    /// candidate with correlation `t2.a = t1.a`, but
+    /// `EXISTS (SELECT * FROM t2 WHERE t2.a = t1.a OR t2.b > 1)` is not. Nor is
+    /// `EXISTS (SELECT count(*) FROM t2 WHERE t2.a = t1.a)`, as the aggregate
+    /// always returns a row.
+    ///
+    /// Returns `None` for any other expression, including uncorrelated
+    /// `EXISTS` subqueries.
+    pub fn exists_to_semijoin_marker(&self) -> Option<SemiJoinCandidate> {
+        /// Collects the correlated conjuncts of `plan` and returns false if the
+        /// correlation is not simple. `on_simple_path` is true if all ancestors
+        /// of `plan` preserve the existence of rows.
+        fn collect_correlated_predicates(
+            plan: &LogicalPlan,
+            on_simple_path: bool,
+            correlated_predicates: &mut Vec<Expr>,
+        ) -> Result<bool> {
+            if let LogicalPlan::Filter(Filter { predicate, .. }) = plan {
+                for conjunct in split_conjunction(predicate) {
+                    if !conjunct.contains_outer() {
+                        continue;
+                    }
+                    if !on_simple_path
+                        || conjunct.exists(|expr| {
+                            Ok(matches!(
+                                expr,
+                                Expr::BinaryExpr(BinaryExpr {
+                                    op: Operator::Or,
+                                    ..
+                                })
+                            ))
+                        })?
+                    {
+                        return Ok(false);
+                    }
+                    correlated_predicates.push(conjunct.clone());
+                }
+            } else {
+                let mut correlated = false;
+                plan.apply_expressions(|expr| {
+                    correlated = expr.contains_outer();
+                    Ok(if correlated {
+                        TreeNodeRecursion::Stop
+                    } else {
+                        TreeNodeRecursion::Continue
+                    })
+                })?;
+                if correlated {
+                    return Ok(false);
+                }
+            }
+
+            let on_simple_path = on_simple_path
+                && match plan {
+                    LogicalPlan::Projection(_)
+                    | LogicalPlan::Filter(_)
+                    | LogicalPlan::SubqueryAlias(_) => true,
+                    LogicalPlan::Sort(sort) => sort.fetch.is_none(),
+                    _ => false,
+                };
+            for input in plan.inputs() {
+                if !collect_correlated_predicates(
+                    input,
+                    on_simple_path,
+                    correlated_predicates,
+                )? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+
+        let Expr::Exists(Exists { subquery, negated }) = self else {
+            return None;
+        };
+        if subquery.outer_ref_columns.is_empty() {
+            return None;
+        }
+
+        let mut correlated_predicates = vec![];
+        if !collect_correlated_predicates(
+            &subquery.subquery,
+            true,
+            &mut correlated_predicates,
+        )
+        .ok()?
+        {
+            return None;
+        }
+        conjunction(correlated_predicates).map(|correlation| SemiJoinCandidate {
+            subquery: subquery.clone(),
+            negated: *negated,
+            correlation,
+        })
+    }
+
+    /// Estimates the selectivity of this predicate, i.e. the fraction of rows
+    /// for which it evaluates to `true`, using the column statistics provided
+    /// by `stats`.
+    ///
+    /// Selectivities are combined bottom-up:
+    /// * `col = literal`: `1 / distinct_count(col)`
+    /// * `col != literal`: `1 - 1 / distinct_count(col)`
+    /// * `a AND b`: `s(a) * s(b)`
+    /// * `a OR b`: `s(a) + s(b) - s(a) * s(b)`
+    /// * `NOT a`: `1 - s(a)`
+    /// * `true` / `false` literals: `1` / `0`
+    ///
+    /// Returns `None` if the statistics required for the estimate are missing
+    /// or the predicate contains an unsupported expression.
+    pub fn estimate_selectivity<S: Fn(&Column) -> Option<ColumnStatistics>>(
+        &self,
+        stats: &S,
+    ) -> Option<f64> {
+        match self {
+            Expr::BinaryExpr(BinaryExpr { left, op, right }) => match op {
+                Operator::And => {
+                    let left = left.estimate_selectivity(stats)?;
+                    let right = right.estimate_selectivity(stats)?;
+                    Some(left * right)
+                }
+                Operator::Or => {
+                    let left = left.estimate_selectivity(stats)?;
+                    let right = right.estimate_selectivity(stats)?;
+                    Some(left + right - left * right)
+                }
+                Operator::Eq | Operator::NotEq => {
+                    let column = match (left.as_ref(), right.as_ref()) {
+                        (Expr::Column(c), Expr::Literal(_))
+                        | (Expr::Literal(_), Expr::Column(c)) => c,
+                        _ => return None,
+                    };
+                    let distinct_count = *stats(column)?.distinct_count.get_value()?;
+                    if distinct_count == 0 {
+                        return None;
+                    }
+                    let selectivity = 1.0 / distinct_count as f64;
+                    Some(if *op == Operator::Eq {
+                        selectivity
+                    } else {
+                        1.0 - selectivity
+                    })
+                }
+                _ => None,
+            },
+            Expr::Not(expr) => Some(1.0 - expr.estimate_selectivity(stats)?),
+            Expr::Literal(ScalarValue::Boolean(Some(value))) => {
+                Some(if *value { 1.0 } else { 0.0 })
+            }
+            _ => None,
+        }
+    }
+
+    /// Recursively rewrites comparisons into a canonical form so that
+    /// equivalent expressions are represented the same way, which helps plan
+    /// caching for example.
+    ///
+    /// Comparisons with a literal on the left and a non-literal on the right
+    /// side are reordered so that the literal is on the right, flipping the
+    /// operator to preserve the semantics (e.g. `5 < a` becomes `a > 5`).
+    ///
+    /// Note that there is no need to rewrite synonymous operator spellings,
+    /// e.g. both `!=` and `<>` are already represented as [`Operator::NotEq`].
+    pub fn canonicalize_operators(self) -> Result<Transformed<Expr>> {
+        self.transform_up(|expr| match expr {
+            Expr::BinaryExpr(BinaryExpr { left, op, right })
+                if matches!(left.as_ref(), Expr::Literal(_))
+                    && !matches!(right.as_ref(), Expr::Literal(_)) =>
+            {
+                Ok(match op.swap() {
+                    Some(swapped_op) => {
+                        Transformed::yes(binary_expr(*right, swapped_op, *left))
+                    }
+                    None => {
+                        Transformed::no(Expr::BinaryExpr(BinaryExpr { left, op, right }))
+                    }
+                })
+            }
+            _ => Ok(Transformed::no(expr)),
+        })
+    }
+
+    /// Same as [`TreeNode::transform_up`], but the subtrees whose root `prune`
+    /// returns true for are skipped entirely.
+    ///
+    /// `prune` is checked before the subtree is visited, so neither the root of
+    /// a pruned subtree nor its descendants are passed to `f`. This is useful
+    /// for rules that must not touch some kinds of expressions, e.g. subqueries.
+    pub fn transform_up_pruning<
+        F: Fn(Expr) -> Result<Transformed<Expr>>,
+        P: Fn(&Expr) -> bool,
+    >(
+        self,
+        prune: P,
+        f: &F,
+    ) -> Result<Transformed<Expr>> {
+        fn transform_up_pruning_impl<
+            F: Fn(Expr) -> Result<Transformed<Expr>>,
+            P: Fn(&Expr) -> bool,
+        >(
+            expr: Expr,
+            prune: &P,
+            f: &F,
+        ) -> Result<Transformed<Expr>> {
+            if prune(&expr) {
+                return Ok(Transformed::no(expr));
+            }
+            expr.map_children(|c| transform_up_pruning_impl(c, prune, f))?
+                .transform_parent(f)
+        }
+
+        transform_up_pruning_impl(self, &prune, f)
+    }
+
+    /// Recursively absorbs `NOT` into the `negated` flag of the negated
+    /// expression where possible.
+    ///
+    /// For example `NOT (a IN (1, 2))` becomes `a NOT IN (1, 2)`, and
+    /// `NOT (a NOT BETWEEN 1 AND 2)` becomes `a BETWEEN 1 AND 2`. `IN` lists,
+    /// `BETWEEN`, `LIKE` and `SIMILAR TO` expressions are supported, and double
+    /// negations are removed.
+    pub fn absorb_not(self) -> Result<Transformed<Expr>> {
+        self.transform_down(|mut expr| {
+            let mut transformed = false;
+            while let Expr::Not(inner) = expr {
+                expr = match *inner {
+                    Expr::Not(inner) => *inner,
+                    Expr::InList(mut in_list) => {
+                        in_list.negated = !in_list.negated;
+                        Expr::InList(in_list)
+                    }
+                    Expr::Between(mut between) => {
+                        between.negated = !between.negated;
+                        Expr::Between(between)
+                    }
+                    Expr::Like(mut like) => {
+                        like.negated = !like.negated;
+                        Expr::Like(like)
+                    }
+                    Expr::SimilarTo(mut like) => {
+                        like.negated = !like.negated;
+                        Expr::SimilarTo(like)
+                    }
+                    inner => {
+                        expr = Expr::Not(Box::new(inner));
+                        break;
+                    }
+                };
+                transformed = true;
+            }
+            Ok(Transformed::new_transformed(expr, transformed))
+        })
+    }
+
+    /// Replaces every occurrence of `target` in the expression with
+    /// `replacement`, e.g. with a column that holds the precomputed value of a
+    /// common subexpression.
+    ///
+    /// Occurrences are found by [`PartialEq`] in bottom-up order, so
+    /// `replacement` itself is not searched for further occurrences.
+    pub fn replace_all(
+        self,
+        target: &Expr,
+        replacement: &Expr,
+    ) -> Result<Transformed<Expr>> {
+        self.transform_up(|expr| {
+            Ok(if &expr == target {
+                Transformed::yes(replacement.clone())
+            } else {
+                Transformed::no(expr)
+            })
+        })
+    }
+
+    /// Recursively rewrites `SIMILAR TO` expressions whose pattern is a string
+    /// literal that uses no regular expression features besides `%` and `_`
+    /// into `LIKE`, or into (in)equality if the pattern has no wildcards.
+    ///
+    /// For example `a SIMILAR TO 'ab%'` becomes `a LIKE 'ab%'` and
+    /// `a NOT SIMILAR TO 'ab'` becomes `a != 'ab'`. Case insensitive matches
+    /// are never rewritten to equality. Patterns that contain an escape
+    /// character (`\` by default) are not rewritten.
+    pub fn similar_to_like(self) -> Result<Transformed<Expr>> {
+        fn is_simple_pattern(pattern: &str, escape_char: char) -> bool {
+            !pattern.chars().any(|c| {
+                c == escape_char
+                    || matches!(
+                        c,
+                        '|' | '*' | '+' | '?' | '{' | '}' | '(' | ')' | '[' | ']'
+                    )
+            })
+        }
+
+        self.transform_up(|expr| match expr {
+            Expr::SimilarTo(like) => {
+                let pattern = match like.pattern.as_ref() {
+                    Expr::Literal(
+                        ScalarValue::Utf8(Some(pattern))
+                        | ScalarValue::LargeUtf8(Some(pattern))
+                        | ScalarValue::Utf8View(Some(pattern)),
+                    ) => pattern,
+                    _ => return Ok(Transformed::no(Expr::SimilarTo(like))),
+                };
+                if !is_simple_pattern(pattern, like.escape_char.unwrap_or('\\')) {
+                    return Ok(Transformed::no(Expr::SimilarTo(like)));
+                }
+                let has_wildcards = pattern.contains(['%', '_']);
+                Ok(Transformed::yes(
+                    if has_wildcards || like.case_insensitive {
+                        Expr::Like(like)
+                    } else {
+                        let op = if like.negated {
+                            Operator::NotEq
+                        } else {
+                            Operator::Eq
+                        };
+                        binary_expr(*like.expr, op, *like.pattern)
+                    },
+                ))
+            }
+            _ => Ok(Transformed::no(expr)),
+        })
+    }
+
+    /// Recursively rewrites `CASE` expressions whose `THEN` and `ELSE` branches
+    /// are all non-null boolean literals into equivalent boolean expressions.
+    ///
+    /// For example `CASE WHEN a THEN true WHEN b THEN true ELSE false END` becomes
+    /// `a IS TRUE OR b IS TRUE` and `CASE WHEN a THEN false ELSE true END`
+    /// becomes `a IS NOT TRUE`.
+    ///
+    /// Note that `IS TRUE` and `IS NOT TRUE` are required to preserve the
+    /// semantics when the `WHEN` conditions evaluate to null, as `CASE` never
+    /// returns null in these shapes. `CASE` expressions with a base expression,
+    /// without an `ELSE` branch or with null results are not rewritten.
+    pub fn case_to_boolean(self) -> Result<Transformed<Expr>> {
+        fn as_bool_literal(expr: &Expr) -> Option<bool> {
+            match expr {
+                Expr::Literal(ScalarValue::Boolean(Some(b))) => Some(*b),
+                _ => None,
+            }
+        }
+
+        self.transform_up(|expr| match expr {
+            Expr::Case(Case {
+                expr: None,
+                when_then_expr,
+                else_expr: Some(else_expr),
+            }) if as_bool_literal(&else_expr).is_some()
+                && when_then_expr
+                    .iter()
+                    .all(|(_, then)| as_bool_literal(then).is_some()) =>
+            {
+                let result = when_then_expr.into_iter().rev().fold(
+                    *else_expr,
+                    |result, (when, then)| match (as_bool_literal(&then), &result) {
+                        // `WHEN when THEN true ELSE false`
+                        (
+                            Some(true),
+                            Expr::Literal(ScalarValue::Boolean(Some(false))),
+                        ) => when.is_true(),
+                        (Some(true), _) => when.is_true().or(result),
+                        // `WHEN when THEN false ELSE true`
+                        (_, Expr::Literal(ScalarValue::Boolean(Some(true)))) => {
+                            when.is_not_true()
+                        }
+                        _ => when.is_not_true().and(result),
+                    },
+                );
+                Ok(Transformed::yes(result))
+            }
+            _ => Ok(Transformed::no(expr)),
+        })
+    }
+
+    /// Recursively removes the `ORDER BY` clause of aggregate functions whose
+    /// result doesn't depend on the order of their input, e.g.
+    /// `SUM(x ORDER BY y)` becomes `SUM(x)`.
+    ///
+    /// Only aggregates with [`AggregateOrderSensitivity::Insensitive`] are
+    /// rewritten. The `ORDER BY` of order sensitive aggregates like `ARRAY_AGG`
+    /// or `FIRST_VALUE` is kept, even if ordering is only
+    /// [`AggregateOrderSensitivity::Beneficial`] for them.
+    ///
+    /// [`AggregateOrderSensitivity::Insensitive`]: crate::utils::AggregateOrderSensitivity::Insensitive
+    /// [`AggregateOrderSensitivity::Beneficial`]: crate::utils::AggregateOrderSensitivity::Beneficial
+    pub fn drop_redundant_aggregate_order_by(self) -> Result<Transformed<Expr>> {
+        self.transform_up(|expr| match expr {
+            Expr::AggregateFunction(AggregateFunction {
+                func,
+                args,
+                distinct,
+                filter,
+                order_by: Some(_),
+                null_treatment,
+            }) if func.order_sensitivity().is_insensitive() => Ok(Transformed::yes(
+                Expr::AggregateFunction(AggregateFunction {
+                    func,
+                    args,
+                    distinct,
+                    filter,
+                    order_by: None,
+                    null_treatment,
+                }),
+            )),
+            _ => Ok(Transformed::no(expr)),
+        })
+    }
+
+    /// Recursively canonicalizes the operands of commutative binary operators
+    /// (e.g. `+`, `*`, `AND`, `=` or `IS DISTINCT FROM`) so that e.g. `a + b`
+    /// and `b + a` become the same expression.
+    ///
+    /// The operands are ordered by the [`PartialOrd`] implementation of `Expr`,
+    /// which is deterministic for a given expression. Only the two operands of
+    /// each binary expression are swapped, so differently associated chains
+    /// such as `(a + b) + c` and `a + (b + c)` stay different.
+    pub fn normalize(self) -> Transformed<Expr> {
+        self.transform_up(|expr| {
+            Ok(match expr {
+                Expr::BinaryExpr(BinaryExpr { left, op, right })
+                    if matches!(
+                        op,
+                        Operator::Eq
+                            | Operator::NotEq
+                            | Operator::IsDistinctFrom
+                            | Operator::IsNotDistinctFrom
+                            | Operator::Plus
+                            | Operator::Multiply
+                            | Operator::And
+                            | Operator::Or
+                            | Operator::BitwiseAnd
+                            | Operator::BitwiseOr
+                            | Operator::BitwiseXor
+                    ) && right < left =>
+                {
+                    Transformed::yes(Expr::BinaryExpr(BinaryExpr::new(right, op, left)))
+                }
+                _ => Transformed::no(expr),
+            })
+        })
+        .expect("traversal is infallible")
+    }
+
+    /// Reorders the top level conjuncts of an `AND` chain in ascending order of
+    /// their [`Self::complexity`], so that cheap predicates are evaluated
+    /// before expensive ones (e.g. subqueries) by engines that evaluate
+    /// conjunctions left to right.
+    ///
+    /// Conjuncts with equal complexity keep their relative order. The
+    /// expression is not changed if any of its conjuncts is volatile, as the
+    /// reordering could change the result.
+    pub fn reorder_conjuncts_by_cost(self) -> Transformed<Expr> {
+        let (is_volatile, mut costs) = {
+            let conjuncts = split_conjunction(&self);
+            let is_volatile = conjuncts.iter().any(|c| {
+                c.exists(|e| Ok(e.is_volatile_node()))
+                    .expect("traversal is infallible")
+            });
+            let costs = conjuncts
+                .into_iter()
+                .map(Expr::complexity)
+                .enumerate()
+                .collect::<Vec<_>>();
+            (is_volatile, costs)
+        };
+        costs.sort_by_key(|(_, cost)| *cost);
+        if is_volatile || costs.iter().enumerate().all(|(i, (index, _))| i == *index) {
+            return Transformed::no(self);
+        }
+
+        let mut conjuncts = split_conjunction_owned(self)
+            .into_iter()
+            .map(Some)
+            .collect::<Vec<_>>();
+        let reordered = costs.into_iter().map(|(index, _)| {
+            conjuncts[index].take().expect("conjunct is not taken yet")
+        });
+        Transformed::yes(conjunction(reordered).expect("there are multiple conjuncts"))
+    }
+
+    /// Recursively folds [`Expr::Negative`] over numeric literals into negated
+    /// literals, e.g. `-(5)` becomes the literal `-5`.
+    ///
+    /// This normalizes the different representations of negative literals.
+    /// Negations that can't be folded, e.g. because negating `i64::MIN`
+    /// overflows, are left unchanged.
+    pub fn fold_negative_literals(self) -> Result<Transformed<Expr>> {
+        self.transform_up(|expr| match expr {
+            Expr::Negative(inner) => match inner.as_ref() {
+                Expr::Literal(value) if value.data_type().is_numeric() => {
+                    match value.arithmetic_negate() {
+                        Ok(negated) => Ok(Transformed::yes(Expr::Literal(negated))),
+                        Err(_) => Ok(Transformed::no(Expr::Negative(inner))),
+                    }
+                }
+                _ => Ok(Transformed::no(Expr::Negative(inner))),
+            },
+            _ => Ok(Transformed::no(expr)),
+        })
+    }
+
+    /// Recursively simplifies `CASE` expressions with constant `WHEN` conditions.
+    ///
+    /// Branches with a `false` or null condition are removed, as they can never
+    /// be taken. A branch with a `true` condition is always taken if it is
+    /// reached, so it becomes the `ELSE` branch and the following branches are
+    /// removed. If only the `ELSE` branch remains then the `CASE` expression is
+    /// replaced with it, e.g. `CASE WHEN true THEN x ELSE y END` becomes `x`.
+    ///
+    /// `CASE` expressions with a base expression are not rewritten, nor are
+    /// the ones whose all branches would be removed without an `ELSE` branch,
+    /// as the type of their null result is not known.
+    pub fn simplify_case_with_constant_predicate(self) -> Result<Transformed<Expr>> {
+        fn as_constant_predicate(expr: &Expr) -> Option<bool> {
+            match expr {
+                Expr::Literal(ScalarValue::Boolean(Some(b))) => Some(*b),
+                Expr::Literal(ScalarValue::Boolean(None) | ScalarValue::Null) => {
+                    Some(false)
+                }
+                _ => None,
+            }
+        }
+
+        self.transform_up(|expr| match expr {
+            Expr::Case(Case {
+                expr: None,
+                when_then_expr,
+                else_expr,
+            }) if when_then_expr
+                .iter()
+                .any(|(when, _)| as_constant_predicate(when).is_some())
+                && (else_expr.is_some()
+                    || when_then_expr
+                        .iter()
+                        .any(|(when, _)| as_constant_predicate(when) != Some(false))) =>
+            {
+                let mut new_when_then_expr = vec![];
+                let mut new_else_expr = else_expr;
+                for (when, then) in when_then_expr {
+                    match as_constant_predicate(&when) {
+                        Some(true) => {
+                            new_else_expr = Some(then);
+                            break;
+                        }
+                        Some(false) => {}
+                        None => new_when_then_expr.push((when, then)),
+                    }
+                }
+                Ok(Transformed::yes(if new_when_then_expr.is_empty() {
+                    *new_else_expr.expect("CASE has an ELSE branch or a true condition")
+                } else {
+                    Expr::Case(Case {
+                        expr: None,
+                        when_then_expr: new_when_then_expr,
+                        else_expr: new_else_expr,
+                    })
+                }))
+            }
+            _ => Ok(Transformed::no(expr)),
+        })
+    }
+
+    /// Recursively simplifies arithmetic identities involving zero and one:
+    /// * `x + 0`, `0 + x`, `x - 0`, `x * 1`, `1 * x` and `x / 1` to `x`
+    /// * `0 - x` to `-x`
+    /// * `x * 0` and `0 * x` to `0`, if `x` is not nullable, not volatile and not
+    ///   a floating point value (as `NaN * 0` is `NaN`) according to `schema`
+    ///
+    /// The operands are expected to have been coerced to the same type, so the
+    /// simplifications don't change the type of the expression.
+    pub fn simplify_arithmetic_identities(
+        self,
+        schema: &dyn ExprSchema,
+    ) -> Result<Transformed<Expr>> {
+        fn is_value(expr: &Expr, value: fn(&DataType) -> Result<ScalarValue>) -> bool {
+            matches!(expr, Expr::Literal(v) if !v.is_null()
+                && value(&v.data_type()).is_ok_and(|value| &value == v))
+        }
+        fn is_zero(expr: &Expr) -> bool {
+            is_value(expr, ScalarValue::new_zero)
+        }
+        fn is_one(expr: &Expr) -> bool {
+            is_value(expr, ScalarValue::new_one)
+        }
+        fn can_be_zeroed(expr: &Expr, schema: &dyn ExprSchema) -> Result<bool> {
+            Ok(!expr.is_volatile()?
+                && !expr.nullable(schema)?
+                && !expr.get_type(schema)?.is_floating())
+        }
+
+        self.transform_up(|expr| {
+            let Expr::BinaryExpr(BinaryExpr { left, op, right }) = expr else {
+                return Ok(Transformed::no(expr));
+            };
+            Ok(match op {
+                Operator::Plus | Operator::Minus if is_zero(&right) => {
+                    Transformed::yes(*left)
+                }
+                Operator::Plus if is_zero(&left) => Transformed::yes(*right),
+                Operator::Minus if is_zero(&left) => {
+                    Transformed::yes(Expr::Negative(right))
+                }
+                Operator::Multiply | Operator::Divide if is_one(&right) => {
+                    Transformed::yes(*left)
+                }
+                Operator::Multiply if is_one(&left) => Transformed::yes(*right),
+                Operator::Multiply
+                    if is_zero(&right) && can_be_zeroed(&left, schema)? =>
+                {
+                    Transformed::yes(*right)
+                }
+                Operator::Multiply
+                    if is_zero(&left) && can_be_zeroed(&right, schema)? =>
+                {
+                    Transformed::yes(*left)
+                }
+                _ => Transformed::no(Expr::BinaryExpr(BinaryExpr { left, op, right })),
+            })
+        })
+    }
+
+    /// Returns true if `self` and `other` are known to be semantically equal.
+    ///
+    /// The check is conservative: both expressions are canonicalized with
+    /// [`Self::canonicalize_operators`] and [`Self::normalize`], and then
+    /// compared structurally. Volatile expressions are never considered equal,
+    /// not even to themselves.
+    pub fn provably_equal(&self, other: &Expr) -> bool {
+        fn canonicalize(expr: Expr) -> Result<Expr> {
+            Ok(expr.canonicalize_operators()?.data.normalize().data)
+        }
+
+        if !matches!(
+            (self.is_volatile(), other.is_volatile()),
+            (Ok(false), Ok(false))
+        ) {
+            return false;
+        }
+        match (canonicalize(self.clone()), canonicalize(other.clone())) {
+            (Ok(left), Ok(right)) => left == right,
+            _ => false,
+        }
+    }
+
     /// Return `self IN <list>` if `negated` is false, otherwise
     /// return `self NOT IN <list>`.a
     pub fn in_list(self, list: Vec<Expr>, negated: bool) -> Expr {
@@ -1562,6 +2368,81 @@ impl Expr {
         .expect("traversal is infallible");
     }
 
+    /// Recursively replaces every [`Expr::Column`] in this expression with the
+    /// expression `f` returns for the column.
+    ///
+    /// The result is marked as transformed only if `f` returned an expression
+    /// other than the column itself for any of the columns.
+    pub fn map_columns<F: FnMut(Column) -> Result<Expr>>(
+        self,
+        mut f: F,
+    ) -> Result<Transformed<Expr>> {
+        self.transform_up(|expr| match expr {
+            Expr::Column(c) => {
+                let original = c.clone();
+                let expr = f(c)?;
+                let transformed = !matches!(&expr, Expr::Column(c) if c == &original);
+                Ok(Transformed::new_transformed(expr, transformed))
+            }
+            _ => Ok(Transformed::no(expr)),
+        })
+    }
+
+    /// Recursively applies `f` to the names in this expression in place, without
+    /// changing the structure of the expression.
+    ///
+    /// The names are the [`Alias`] names, the parts of the [`Alias`] and
+    /// [`Column`] relations, and the [`Placeholder`] ids. Column names are not
+    /// rewritten.
+    pub fn rewrite_names<F: FnMut(&mut String)>(&mut self, mut f: F) {
+        fn rewrite_part<F: FnMut(&mut String)>(part: &mut Arc<str>, f: &mut F) {
+            let mut name = part.to_string();
+            f(&mut name);
+            *part = name.into();
+        }
+
+        fn rewrite_relation<F: FnMut(&mut String)>(
+            relation: &mut Option<TableReference>,
+            f: &mut F,
+        ) {
+            match relation {
+                Some(TableReference::Bare { table }) => rewrite_part(table, f),
+                Some(TableReference::Partial { schema, table }) => {
+                    rewrite_part(schema, f);
+                    rewrite_part(table, f);
+                }
+                Some(TableReference::Full {
+                    catalog,
+                    schema,
+                    table,
+                }) => {
+                    rewrite_part(catalog, f);
+                    rewrite_part(schema, f);
+                    rewrite_part(table, f);
+                }
+                None => {}
+            }
+        }
+
+        *self = mem::take(self)
+            .transform_up(|mut expr| {
+                match &mut expr {
+                    Expr::Alias(Alias { relation, name, .. }) => {
+                        rewrite_relation(relation, &mut f);
+                        f(name);
+                    }
+                    Expr::Column(Column { relation, .. }) => {
+                        rewrite_relation(relation, &mut f)
+                    }
+                    Expr::Placeholder(Placeholder { id, .. }) => f(id),
+                    _ => {}
+                }
+                Ok(Transformed::no(expr))
+            })
+            .data()
+            .expect("traversal is infallible");
+    }
+
     /// Returns true if there are any column references in this Expr
     pub fn any_column_refs(&self) -> bool {
         self.exists(|expr| Ok(matches!(expr, Expr::Column(_))))
@@ -1589,6 +2470,43 @@ impl Expr {
         self.exists(|expr| Ok(expr.is_volatile_node()))
     }
 
+    /// Returns true if the expression contains a scalar, aggregate or window
+    /// function with [`Volatility::Volatile`].
+    ///
+    /// Unlike [`Self::is_volatile`], this also considers aggregate and window
+    /// functions. The traversal stops at the first volatile function.
+    pub fn contains_volatile(&self) -> bool {
+        self.exists(|expr| {
+            let volatility = match expr {
+                Expr::ScalarFunction(func) => func.func.signature().volatility,
+                Expr::AggregateFunction(func) => func.func.signature().volatility,
+                Expr::WindowFunction(func) => func.fun.signature().volatility,
+                _ => return Ok(false),
+            };
+            Ok(volatility == Volatility::Volatile)
+        })
+        .expect("traversal is infallible")
+    }
+
+    /// Returns a rough estimate of the cost of evaluating the expression.
+    ///
+    /// Each node of the expression costs 1, except scalar functions that cost
+    /// 10 and subqueries that cost 100, as those are usually much more expensive
+    /// to evaluate than column references, literals or operators.
+    pub fn complexity(&self) -> usize {
+        let mut complexity = 0;
+        self.apply(|expr| {
+            complexity += match expr {
+                Expr::ScalarSubquery(_) | Expr::Exists(_) | Expr::InSubquery(_) => 100,
+                Expr::ScalarFunction(_) => 10,
+                _ => 1,
+            };
+            Ok(TreeNodeRecursion::Continue)
+        })
+        .expect("traversal is infallible");
+        complexity
+    }
+
     /// Recursively find all [`Expr::Placeholder`] expressions, and
     /// to infer their [`DataType`] from the context of their use.
     ///
@@ -1616,7 +2534,140 @@ impl Expr {
         .data()
     }
 
-    /// Returns true if some of this `exprs` subexpressions may not be evaluated
+    /// Returns the sorted, deduplicated ids of all positional
+    /// [`Expr::Placeholder`]s (`$1`, `$2`, ...) in this expression.
+    ///
+    /// Returns an error if a placeholder id is not of the form `$N` with
+    /// `N >= 1`. Front-ends can use the result to check that the placeholders
+    /// are used contiguously, i.e. that the ids are `1..=N`.
+    pub fn validate_placeholders(&self) -> Result<Vec<usize>> {
+        let mut ids = vec![];
+        self.apply(|expr| {
+            if let Expr::Placeholder(Placeholder { id, .. }) = expr {
+                let idx = id
+                    .strip_prefix('$')
+                    .and_then(|idx| idx.parse::<usize>().ok())
+                    .filter(|idx| *idx > 0);
+                match idx {
+                    Some(idx) => ids.push(idx),
+                    None => return plan_err!("Invalid placeholder id: {id}"),
+                }
+            }
+            Ok(TreeNodeRecursion::Continue)
+        })?;
+        ids.sort_unstable();
+        ids.dedup();
+        Ok(ids)
+    }
+
+    /// Derives the `(min, max)` range of `column` implied by this predicate.
+    ///
+    /// The predicate must be a conjunction of bounds on `column`, like
+    /// `column >= 5`, `10 > column`, `column = 7` or `column BETWEEN 1 AND 9`,
+    /// the ranges implied by the conjuncts are intersected. Returns `None` if
+    /// the predicate contains anything else, e.g. a disjunction, or if the
+    /// bounds can't be compared.
+    ///
+    /// `None` bounds are unbounded. The bounds of the range don't tell whether
+    /// they are inclusive, e.g. both `column > 5` and `column >= 5` result in the
+    /// range `(Some(5), None)`, so all values satisfying the predicate are in
+    /// the range but not vice versa.
+    pub fn derive_range(
+        &self,
+        column: &Column,
+    ) -> Option<(Option<ScalarValue>, Option<ScalarValue>)> {
+        fn non_null_literal(expr: &Expr) -> Option<&ScalarValue> {
+            match expr {
+                Expr::Literal(value) if !value.is_null() => Some(value),
+                _ => None,
+            }
+        }
+
+        // Returns the more restrictive of the two bounds
+        fn intersect(
+            bound: Option<ScalarValue>,
+            other: &ScalarValue,
+            ordering: std::cmp::Ordering,
+        ) -> Option<Option<ScalarValue>> {
+            match bound {
+                Some(bound) => {
+                    let more_restrictive = other.partial_cmp(&bound)? == ordering;
+                    Some(Some(if more_restrictive {
+                        other.clone()
+                    } else {
+                        bound
+                    }))
+                }
+                None => Some(Some(other.clone())),
+            }
+        }
+
+        let is_column = |expr: &Expr| matches!(expr, Expr::Column(c) if c == column);
+        let (mut min, mut max) = (None, None);
+        for conjunct in split_conjunction(self) {
+            let (lower, upper) = match conjunct {
+                Expr::BinaryExpr(BinaryExpr { left, op, right }) => {
+                    let (value, op) = if is_column(left) {
+                        (non_null_literal(right)?, *op)
+                    } else if is_column(right) {
+                        (non_null_literal(left)?, op.swap()?)
+                    } else {
+                        return None;
+                    };
+                    match op {
+                        Operator::Eq => (Some(value), Some(value)),
+                        Operator::Gt | Operator::GtEq => (Some(value), None),
+                        Operator::Lt | Operator::LtEq => (None, Some(value)),
+                        _ => return None,
+                    }
+                }
+                Expr::Between(Between {
+                    expr,
+                    negated: false,
+                    low,
+                    high,
+                }) if is_column(expr) => {
+                    (Some(non_null_literal(low)?), Some(non_null_literal(high)?))
+                }
+                _ => return None,
+            };
+            if let Some(lower) = lower {
+                min = intersect(min, lower, std::cmp::Ordering::Greater)?;
+            }
+            if let Some(upper) = upper {
+                max = intersect(max, upper, std::cmp::Ordering::Less)?;
+            }
+        }
+        Some((min, max))
+    }
+
+    /// Checks that the `FILTER` clauses of the aggregate functions in this
+    /// expression don't contain aggregate functions, like in
+    /// `SUM(x) FILTER (WHERE COUNT(y) > 0)`.
+    ///
+    /// Returns a planning error with the offending aggregate function otherwise.
+    pub fn validate_aggregate_filters(&self) -> Result<()> {
+        self.apply(|expr| {
+            if let Expr::AggregateFunction(AggregateFunction {
+                filter: Some(filter),
+                ..
+            }) = expr
+            {
+                filter.apply(|filter_expr| {
+                    if let Expr::AggregateFunction(_) = filter_expr {
+                        return plan_err!(
+                            "Aggregate function {filter_expr} is not allowed in the FILTER clause of {expr}"
+                        );
+                    }
+                    Ok(TreeNodeRecursion::Continue)
+                })?;
+            }
+            Ok(TreeNodeRecursion::Continue)
+        })
+        .map(|_| ())
+    }
+
+    /// Returns true if some of this `exprs` subexpressions may not be evaluated
     /// and thus any side effects (like divide by zero) may not be encountered
     pub fn short_circuits(&self) -> bool {
         match self {
@@ -2392,11 +3443,20 @@ pub fn physical_name(expr: &Expr) -> Result<String> {
 
 #[cfg(test)]
 mod test {
-    use crate::expr_fn::col;
+    use crate::expr_fn::{
+        bitwise_and, cast, col, create_udaf, exists, in_subquery, out_ref_col,
+        placeholder, scalar_subquery, when,
+    };
+    use crate::logical_plan::table_scan;
+    use crate::test::function_stub::{count, sum, sum_udaf};
     use crate::{
-        case, lit, qualified_wildcard, wildcard, wildcard_with_options, ColumnarValue,
-        ScalarUDF, ScalarUDFImpl, Volatility,
+        case, lit, qualified_wildcard, wildcard, wildcard_with_options, AggregateUDF,
+        ColumnarValue, ScalarUDF, ScalarUDFImpl, Volatility,
     };
+    use arrow::datatypes::{Field, Schema};
+    use datafusion_common::not_impl_err;
+    use datafusion_common::stats::Precision;
+    use datafusion_common::tree_node::Template;
     use sqlparser::ast;
     use sqlparser::ast::{Ident, IdentWithAlias};
     use std::any::Any;
@@ -2550,6 +3610,509 @@ mod test {
 
     use super::*;
 
+    /// A stub for functions implemented in the `datafusion-functions` crate
+    #[derive(Debug)]
+    struct FunctionStub {
+        name: &'static str,
+        signature: Signature,
+    }
+
+    impl ScalarUDFImpl for FunctionStub {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn signature(&self) -> &Signature {
+            &self.signature
+        }
+
+        fn return_type(&self, arg_types: &[DataType]) -> Result<DataType> {
+            Ok(arg_types[0].clone())
+        }
+
+        fn invoke(&self, _args: &[ColumnarValue]) -> Result<ColumnarValue> {
+            not_impl_err!("{} stub", self.name)
+        }
+    }
+
+    fn function_stub(
+        name: &'static str,
+        volatility: Volatility,
+        args: Vec<Expr>,
+    ) -> Expr {
+        let udf = Arc::new(ScalarUDF::from(FunctionStub {
+            name,
+            signature: Signature::variadic_any(volatility),
+        }));
+        Expr::ScalarFunction(ScalarFunction::new_udf(udf, args))
+    }
+
+    fn coalesce(args: Vec<Expr>) -> Expr {
+        function_stub("coalesce", Volatility::Immutable, args)
+    }
+
+    fn concat(args: Vec<Expr>) -> Expr {
+        function_stub("concat", Volatility::Immutable, args)
+    }
+
+    fn random() -> Expr {
+        function_stub("random", Volatility::Volatile, vec![])
+    }
+
+    #[test]
+    fn test_flatten_coalesce_nested() -> Result<()> {
+        let expr = coalesce(vec![col("a"), coalesce(vec![col("b"), col("c")])]);
+        let expected = coalesce(vec![col("a"), col("b"), col("c")]);
+        assert_eq!(expr.flatten_coalesce()?, Transformed::yes(expected));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flatten_coalesce_flat() -> Result<()> {
+        let expr = coalesce(vec![col("a"), col("b"), col("c")]);
+        assert_eq!(expr.clone().flatten_coalesce()?, Transformed::no(expr));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flatten_coalesce_non_null_literal() -> Result<()> {
+        // `coalesce(a, coalesce(NULL, 1, b), c)` -> `coalesce(a, NULL, 1)`
+        let expr = coalesce(vec![
+            col("a"),
+            coalesce(vec![lit(ScalarValue::Int32(None)), lit(1), col("b")]),
+            col("c"),
+        ]);
+        let expected = coalesce(vec![col("a"), lit(ScalarValue::Int32(None)), lit(1)]);
+        assert_eq!(expr.flatten_coalesce()?, Transformed::yes(expected));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flatten_concat_nested() -> Result<()> {
+        let expr = concat(vec![concat(vec![col("a"), col("b")]), col("c")]);
+        let expected = concat(vec![col("a"), col("b"), col("c")]);
+        assert_eq!(expr.flatten_concat()?, Transformed::yes(expected));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flatten_concat_adjacent_literals() -> Result<()> {
+        let expr = concat(vec![lit("a"), lit("b"), col("x"), lit("c")]);
+        let expected = concat(vec![lit("ab"), col("x"), lit("c")]);
+        assert_eq!(expr.flatten_concat()?, Transformed::yes(expected));
+
+        // literals become adjacent after flattening
+        let expr = concat(vec![concat(vec![col("x"), lit("a")]), lit("b")]);
+        let expected = concat(vec![col("x"), lit("ab")]);
+        assert_eq!(expr.flatten_concat()?, Transformed::yes(expected));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flatten_concat_flat() -> Result<()> {
+        let expr = concat(vec![col("a"), lit("b"), col("c")]);
+        assert_eq!(expr.clone().flatten_concat()?, Transformed::no(expr));
+
+        Ok(())
+    }
+
+    fn correlated_exists(predicate: Expr) -> Result<Expr> {
+        let schema = Schema::new(vec![
+            Field::new("a", DataType::UInt32, false),
+            Field::new("b", DataType::UInt32, false),
+        ]);
+        let plan = table_scan(Some("t2"), &schema, None)?
+            .filter(predicate)?
+            .project(vec![col("t2.a")])?
+            .build()?;
+        Ok(exists(Arc::new(plan)))
+    }
+
+    #[test]
+    fn test_column_refs_nested() {
+        let expr = (col("a") + cast(col("b"), DataType::Int32)).alias("c");
+        assert_eq!(
+            expr.column_refs(),
+            HashSet::from([&Column::new_unqualified("a"), &Column::new_unqualified("b")])
+        );
+    }
+
+    #[test]
+    fn test_column_refs_subquery() -> Result<()> {
+        // columns of the subquery plans are not referenced by the expression
+        let subquery =
+            correlated_exists(col("t2.a").eq(out_ref_col(DataType::UInt32, "t1.a")))?;
+        let Expr::Exists(Exists { subquery, .. }) = subquery else {
+            unreachable!()
+        };
+        let expr = col("t1.b")
+            .gt(scalar_subquery(Arc::clone(&subquery.subquery)))
+            .and(in_subquery(col("t1.c"), subquery.subquery));
+        assert_eq!(
+            expr.column_refs(),
+            HashSet::from([&Column::from("t1.b"), &Column::from("t1.c")])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_map_subqueries() -> Result<()> {
+        let schema = Schema::new(vec![Field::new("a", DataType::UInt32, false)]);
+        let subquery = Arc::new(
+            table_scan(Some("t2"), &schema, None)?
+                .project(vec![col("t2.a")])?
+                .build()?,
+        );
+        let expr = in_subquery(col("t1.a"), Arc::clone(&subquery))
+            .and(col("t1.b").gt(scalar_subquery(subquery)));
+
+        let transformed = expr.map_subqueries(|plan| {
+            plan.transform_up(|plan| match plan {
+                LogicalPlan::TableScan(mut scan) => {
+                    scan.table_name = TableReference::bare("t3");
+                    Ok(Transformed::yes(LogicalPlan::TableScan(scan)))
+                }
+                _ => Ok(Transformed::no(plan)),
+            })
+        })?;
+        assert!(transformed.transformed);
+
+        let mut table_names = vec![];
+        transformed.data.apply(|expr| {
+            if let Expr::InSubquery(InSubquery { subquery, .. })
+            | Expr::ScalarSubquery(subquery) = expr
+            {
+                subquery.subquery.apply(|plan| {
+                    if let LogicalPlan::TableScan(scan) = plan {
+                        table_names.push(scan.table_name.to_string());
+                    }
+                    Ok(TreeNodeRecursion::Continue)
+                })?;
+            }
+            Ok(TreeNodeRecursion::Continue)
+        })?;
+        assert_eq!(table_names, vec!["t3", "t3"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exists_to_semijoin_marker_simple() -> Result<()> {
+        let correlation = col("t2.a").eq(out_ref_col(DataType::UInt32, "t1.a"));
+        let expr = correlated_exists(correlation.clone().and(col("t2.b").gt(lit(1u32))))?;
+        let candidate = expr.exists_to_semijoin_marker().unwrap();
+        assert!(!candidate.negated);
+        assert_eq!(candidate.correlation, correlation);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exists_to_semijoin_marker_disjunction() -> Result<()> {
+        let expr = correlated_exists(
+            col("t2.a")
+                .eq(out_ref_col(DataType::UInt32, "t1.a"))
+                .or(col("t2.b").gt(lit(1u32))),
+        )?;
+        assert_eq!(expr.exists_to_semijoin_marker(), None);
+
+        // uncorrelated subqueries are not candidates either
+        let expr = correlated_exists(col("t2.b").gt(lit(1u32)))?;
+        assert_eq!(expr.exists_to_semijoin_marker(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exists_to_semijoin_marker_aggregate() -> Result<()> {
+        let schema = Schema::new(vec![
+            Field::new("a", DataType::UInt32, false),
+            Field::new("b", DataType::UInt32, false),
+        ]);
+        let correlation = col("t2.a").eq(out_ref_col(DataType::UInt32, "t1.a"));
+
+        // `count(*)` always returns a row, so the `EXISTS` is always true
+        let plan = table_scan(Some("t2"), &schema, None)?
+            .filter(correlation.clone())?
+            .aggregate(Vec::<Expr>::new(), vec![count(lit(1))])?
+            .build()?;
+        assert_eq!(exists(Arc::new(plan)).exists_to_semijoin_marker(), None);
+
+        let plan = table_scan(Some("t2"), &schema, None)?
+            .filter(correlation)?
+            .limit(0, Some(0))?
+            .build()?;
+        assert_eq!(exists(Arc::new(plan)).exists_to_semijoin_marker(), None);
+
+        Ok(())
+    }
+
+    fn column_stats(column: &Column) -> Option<ColumnStatistics> {
+        let distinct_count = match column.name.as_str() {
+            "a" => 10,
+            "b" => 4,
+            _ => return None,
+        };
+        Some(ColumnStatistics {
+            distinct_count: Precision::Exact(distinct_count),
+            ..ColumnStatistics::new_unknown()
+        })
+    }
+
+    #[test]
+    fn test_estimate_selectivity_eq() {
+        let expr = col("a").eq(lit(5));
+        assert_eq!(expr.estimate_selectivity(&column_stats), Some(0.1));
+    }
+
+    #[test]
+    fn test_estimate_selectivity_and() {
+        let expr = col("a").eq(lit(5)).and(col("b").eq(lit(1)));
+        assert_eq!(expr.estimate_selectivity(&column_stats), Some(0.025));
+    }
+
+    #[test]
+    fn test_estimate_selectivity_unknown_stats() {
+        let expr = col("a").eq(lit(5)).and(col("c").eq(lit(1)));
+        assert_eq!(expr.estimate_selectivity(&column_stats), None);
+    }
+
+    #[test]
+    fn test_canonicalize_operators_flips_comparison() -> Result<()> {
+        let expr = lit(5).lt(col("a"));
+        assert_eq!(
+            expr.canonicalize_operators()?,
+            Transformed::yes(col("a").gt(lit(5)))
+        );
+
+        // non-commutative operators are left as is
+        let expr = lit(5) - col("a");
+        assert_eq!(
+            expr.clone().canonicalize_operators()?,
+            Transformed::no(expr)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_canonicalize_operators_not_eq() -> Result<()> {
+        // both `5 <> a` and `5 != a` are represented as `Operator::NotEq`
+        let expr = lit(5).not_eq(col("a")).and(col("b").not_eq(lit(1)));
+        assert_eq!(
+            expr.canonicalize_operators()?,
+            Transformed::yes(col("a").not_eq(lit(5)).and(col("b").not_eq(lit(1))))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_fanout_in_list() {
+        let list = (0..10).map(lit).collect();
+        let expr = col("a").in_list(list, false).and(col("b").eq(lit(1)));
+        // the `InList` has the tested expression and the 10 list items as children
+        assert_eq!(expr.max_fanout(), 11);
+    }
+
+    #[test]
+    fn test_absorb_not_in_list() -> Result<()> {
+        let expr = !col("a").in_list(vec![lit(1), lit(2)], false);
+        assert_eq!(
+            expr.absorb_not()?,
+            Transformed::yes(col("a").in_list(vec![lit(1), lit(2)], true))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_absorb_not_between() -> Result<()> {
+        let expr = !col("a").not_between(lit(1), lit(2));
+        assert_eq!(
+            expr.absorb_not()?,
+            Transformed::yes(col("a").between(lit(1), lit(2)))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_absorb_not_like() -> Result<()> {
+        // `!` already absorbs the negation of `LIKE` but planners, for example,
+        // can still produce `Expr::Not` nodes
+        let expr = Expr::Not(Box::new(col("a").like(lit("%b"))));
+        assert_eq!(
+            expr.absorb_not()?,
+            Transformed::yes(col("a").not_like(lit("%b")))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_absorb_not_double_negation() -> Result<()> {
+        let expr = !!col("a").gt(lit(1));
+        assert_eq!(expr.absorb_not()?, Transformed::yes(col("a").gt(lit(1))));
+
+        // the negation is absorbed after removing the double negation
+        let expr = (!!!col("a").in_list(vec![lit(1)], false)).or(!col("b"));
+        assert_eq!(
+            expr.absorb_not()?,
+            Transformed::yes(col("a").in_list(vec![lit(1)], true).or(!col("b")))
+        );
+
+        let expr = !col("b");
+        assert_eq!(expr.absorb_not()?, Transformed::no(!col("b")));
+
+        Ok(())
+    }
+
+    fn arithmetic_test_schema() -> Result<DFSchema> {
+        DFSchema::try_from(Schema::new(vec![
+            Field::new("a", DataType::Int32, false),
+            Field::new("b", DataType::Int32, true),
+            Field::new("c", DataType::Float64, false),
+        ]))
+    }
+
+    #[test]
+    fn test_simplify_arithmetic_identities() -> Result<()> {
+        let schema = arithmetic_test_schema()?;
+        let cases = vec![
+            (col("b") + lit(0), col("b")),
+            (lit(0) + col("b"), col("b")),
+            (col("b") - lit(0), col("b")),
+            (col("b") * lit(1), col("b")),
+            (lit(1) * col("b"), col("b")),
+            (col("b") / lit(1), col("b")),
+            (col("c") / lit(1.0), col("c")),
+            (lit(0) - col("b"), Expr::Negative(Box::new(col("b")))),
+            (col("a") * lit(0), lit(0)),
+            (lit(0) * col("a"), lit(0)),
+            (
+                (col("a") + lit(0)) * (col("b") * lit(1)),
+                col("a") * col("b"),
+            ),
+        ];
+        for (expr, expected) in cases {
+            assert_eq!(
+                expr.simplify_arithmetic_identities(&schema)?,
+                Transformed::yes(expected)
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_simplify_arithmetic_identities_no_change() -> Result<()> {
+        let schema = arithmetic_test_schema()?;
+        let cases = vec![
+            col("a") + lit(1),
+            col("a") / lit(0),
+            lit(1) / col("a"),
+            // `b` is nullable
+            col("b") * lit(0),
+            // `c` can be `NaN`
+            col("c") * lit(0.0),
+            // `random()` is volatile
+            random() * lit(0),
+        ];
+        for expr in cases {
+            assert_eq!(
+                expr.clone().simplify_arithmetic_identities(&schema)?,
+                Transformed::no(expr)
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_case_to_boolean_or() -> Result<()> {
+        let expr = when(col("a"), lit(true))
+            .when(col("b"), lit(true))
+            .otherwise(lit(false))?;
+        assert_eq!(
+            expr.case_to_boolean()?,
+            Transformed::yes(col("a").is_true().or(col("b").is_true()))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_case_to_boolean_negated() -> Result<()> {
+        let expr = when(col("a"), lit(false)).otherwise(lit(true))?;
+        assert_eq!(
+            expr.case_to_boolean()?,
+            Transformed::yes(col("a").is_not_true())
+        );
+
+        // mixed `THEN` branches
+        let expr = when(col("a"), lit(true))
+            .when(col("b"), lit(false))
+            .otherwise(lit(true))?;
+        assert_eq!(
+            expr.case_to_boolean()?,
+            Transformed::yes(col("a").is_true().or(col("b").is_not_true()))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_case_to_boolean_no_change() -> Result<()> {
+        // non-boolean results
+        let expr = when(col("a"), lit(1)).otherwise(lit(2))?;
+        assert_eq!(expr.clone().case_to_boolean()?, Transformed::no(expr));
+
+        // no `ELSE`, the result is null if nothing matches
+        let expr = when(col("a"), lit(true)).end()?;
+        assert_eq!(expr.clone().case_to_boolean()?, Transformed::no(expr));
+
+        // null result
+        let expr =
+            when(col("a"), lit(ScalarValue::Boolean(None))).otherwise(lit(false))?;
+        assert_eq!(expr.clone().case_to_boolean()?, Transformed::no(expr));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_provably_equal_commutative() {
+        assert!((col("a") + col("b")).provably_equal(&(col("b") + col("a"))));
+        assert!((col("a") + lit(1)).provably_equal(&(lit(1) + col("a"))));
+        assert!(col("a")
+            .eq(col("b"))
+            .and(col("c").gt(lit(1)))
+            .provably_equal(&lit(1).lt(col("c")).and(col("b").eq(col("a")))));
+    }
+
+    #[test]
+    fn test_provably_equal_non_commutative() {
+        assert!(!(col("a") - col("b")).provably_equal(&(col("b") - col("a"))));
+        assert!(!col("a").lt(col("b")).provably_equal(&col("b").lt(col("a"))));
+    }
+
+    #[test]
+    fn test_provably_equal_volatile() {
+        let expr = random();
+        assert!(!expr.provably_equal(&expr));
+        assert!(!(col("a") + random()).provably_equal(&(col("a") + random())));
+    }
+
     #[test]
     fn test_first_value_return_type() -> Result<()> {
         let fun = find_df_window_func("first_value").unwrap();
@@ -2805,4 +4368,511 @@ mod test {
             rename: opt_rename,
         }
     }
+
+    #[test]
+    fn test_validate_placeholders() -> Result<()> {
+        let expr = placeholder("$2") + placeholder("$1") * placeholder("$2");
+        assert_eq!(expr.validate_placeholders()?, vec![1, 2]);
+
+        let expr = placeholder("$1") + placeholder("$3");
+        assert_eq!(expr.validate_placeholders()?, vec![1, 3]);
+
+        assert_eq!(col("a").validate_placeholders()?, Vec::<usize>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_placeholders_malformed() {
+        for id in ["$abc", "$0", "1", "$"] {
+            let expr = placeholder("$1") + placeholder(id);
+            let err = expr.validate_placeholders().unwrap_err();
+            assert_eq!(
+                err.strip_backtrace(),
+                format!("Error during planning: Invalid placeholder id: {id}")
+            );
+        }
+    }
+
+    #[test]
+    fn test_drop_redundant_aggregate_order_by() -> Result<()> {
+        let with_order_by = |func: Arc<AggregateUDF>| {
+            Expr::AggregateFunction(AggregateFunction::new_udf(
+                func,
+                vec![col("x")],
+                false,
+                None,
+                Some(vec![col("y").sort(true, false)]),
+                None,
+            ))
+        };
+
+        let expr = with_order_by(sum_udaf()) + lit(1);
+        let expected = sum(col("x")) + lit(1);
+        let result = expr.drop_redundant_aggregate_order_by()?;
+        assert!(result.transformed);
+        assert_eq!(result.data, expected);
+
+        // order sensitive aggregates keep their ordering
+        let array_agg = Arc::new(create_udaf(
+            "array_agg",
+            vec![DataType::Int32],
+            Arc::new(DataType::new_list(DataType::Int32, true)),
+            Volatility::Immutable,
+            Arc::new(|_| not_impl_err!("array_agg stub")),
+            Arc::new(vec![]),
+        ));
+        let expr = with_order_by(array_agg);
+        let result = expr.clone().drop_redundant_aggregate_order_by()?;
+        assert!(!result.transformed);
+        assert_eq!(result.data, expr);
+        Ok(())
+    }
+
+    #[test]
+    fn test_match_template() -> Result<()> {
+        let node_eq = |e: &Expr, t: &Expr| match (e, t) {
+            (Expr::BinaryExpr(e), Expr::BinaryExpr(t)) => e.op == t.op,
+            _ => e == t,
+        };
+
+        // `?x + 0`
+        let template = Template::node(
+            lit(0) + lit(0),
+            vec![Template::hole("x"), Template::node(lit(0), vec![])],
+        );
+
+        let bindings = (col("a") + lit(0))
+            .match_template(&template, node_eq)
+            .unwrap();
+        assert_eq!(Expr::instantiate(Template::hole("x"), &bindings)?, col("a"));
+
+        assert!((col("a") + lit(1))
+            .match_template(&template, node_eq)
+            .is_none());
+        assert!((col("a") - lit(0))
+            .match_template(&template, node_eq)
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_complexity() -> Result<()> {
+        assert_eq!(col("a").eq(lit(1)).complexity(), 3);
+        assert_eq!(concat(vec![col("a"), lit("b")]).complexity(), 12);
+        assert_eq!(correlated_exists(col("t2.a").eq(lit(1)))?.complexity(), 100);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reorder_conjuncts_by_cost() -> Result<()> {
+        let subquery = || correlated_exists(col("t2.a").eq(lit(1)));
+
+        let expr = subquery()?
+            .and(concat(vec![col("a"), lit("b")]).eq(lit("ab")))
+            .and(col("a").eq(lit(1)));
+        let expected = col("a")
+            .eq(lit(1))
+            .and(concat(vec![col("a"), lit("b")]).eq(lit("ab")))
+            .and(subquery()?);
+        assert_eq!(expr.reorder_conjuncts_by_cost(), Transformed::yes(expected));
+
+        // already ordered
+        let expr = col("a").eq(lit(1)).and(subquery()?);
+        assert_eq!(
+            expr.clone().reorder_conjuncts_by_cost(),
+            Transformed::no(expr)
+        );
+
+        // volatile conjuncts prevent reordering
+        let expr = subquery()?
+            .and(random().gt(lit(0.5)))
+            .and(col("a").eq(lit(1)));
+        assert_eq!(
+            expr.clone().reorder_conjuncts_by_cost(),
+            Transformed::no(expr)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewrite_names() {
+        let mut expr = cast(col("t.a") + placeholder("$p"), DataType::Int64)
+            .alias_qualified(Some("s.t"), "x")
+            .and(Expr::GroupingSet(GroupingSet::Rollup(vec![
+                col("c.s.t.b"),
+                col("c").alias("y"),
+            ])));
+        expr.rewrite_names(|name| *name = name.to_uppercase());
+
+        // unquoted identifiers would be normalized to lower case by `col`
+        let expected = cast(col(r#""T".a"#) + placeholder("$P"), DataType::Int64)
+            .alias_qualified(Some(TableReference::partial("S", "T")), "X")
+            .and(Expr::GroupingSet(GroupingSet::Rollup(vec![
+                col(r#""C"."S"."T".b"#),
+                col("c").alias("Y"),
+            ])));
+        assert_eq!(expr, expected);
+    }
+
+    #[test]
+    fn test_similar_to_like() -> Result<()> {
+        let similar_to = |pattern: &str| {
+            Expr::SimilarTo(Like::new(
+                false,
+                Box::new(col("a")),
+                Box::new(lit(pattern)),
+                None,
+                false,
+            ))
+        };
+
+        let expr = similar_to("a%b_");
+        let expected = col("a").like(lit("a%b_"));
+        assert_eq!(expr.similar_to_like()?, Transformed::yes(expected));
+
+        let expr = !similar_to("ab");
+        assert_eq!(
+            expr.similar_to_like()?,
+            Transformed::yes(col("a").not_eq(lit("ab")))
+        );
+
+        for pattern in ["a|b", "(ab)%", "a*", "[ab]%", "a\\%"] {
+            let expr = similar_to(pattern);
+            assert_eq!(
+                expr.clone().similar_to_like()?,
+                Transformed::no(expr),
+                "{pattern}"
+            );
+        }
+
+        // non-literal patterns are not rewritten
+        let expr = Expr::SimilarTo(Like::new(
+            false,
+            Box::new(col("a")),
+            Box::new(col("b")),
+            None,
+            false,
+        ));
+        assert_eq!(expr.clone().similar_to_like()?, Transformed::no(expr));
+        Ok(())
+    }
+
+    #[test]
+    fn test_derive_range() {
+        let column = Column::from_name("a");
+        let int = |i: i32| Some(ScalarValue::Int32(Some(i)));
+
+        let expr = col("a").gt_eq(lit(5)).and(col("a").lt(lit(10)));
+        assert_eq!(expr.derive_range(&column), Some((int(5), int(10))));
+
+        let expr = col("a")
+            .between(lit(1), lit(8))
+            .and(lit(3).lt(col("a")))
+            .and(col("a").lt_eq(lit(9)));
+        assert_eq!(expr.derive_range(&column), Some((int(3), int(8))));
+
+        let expr = col("a").eq(lit(7)).and(col("a").gt(lit(5)));
+        assert_eq!(expr.derive_range(&column), Some((int(7), int(7))));
+
+        let expr = col("a").lt(lit(10));
+        assert_eq!(expr.derive_range(&column), Some((None, int(10))));
+
+        // disjunctions and bounds on other columns are not supported
+        let expr = col("a").gt_eq(lit(5)).or(col("a").lt(lit(1)));
+        assert_eq!(expr.derive_range(&column), None);
+        let expr = col("a").gt_eq(lit(5)).and(col("b").lt(lit(10)));
+        assert_eq!(expr.derive_range(&column), None);
+        let expr = col("a").not_eq(lit(5));
+        assert_eq!(expr.derive_range(&column), None);
+    }
+
+    #[test]
+    fn test_fold_negative_literals() -> Result<()> {
+        let negative = |expr: Expr| Expr::Negative(Box::new(expr));
+
+        let expr = negative(lit(5i64));
+        assert_eq!(expr.fold_negative_literals()?, Transformed::yes(lit(-5i64)));
+
+        let expr = negative(negative(lit(5i64)));
+        assert_eq!(expr.fold_negative_literals()?, Transformed::yes(lit(5i64)));
+
+        let expr = col("a") + negative(lit(1.5));
+        assert_eq!(
+            expr.fold_negative_literals()?,
+            Transformed::yes(col("a") + lit(-1.5))
+        );
+
+        // overflow
+        let expr = negative(lit(i64::MIN));
+        assert_eq!(
+            expr.clone().fold_negative_literals()?,
+            Transformed::no(expr)
+        );
+
+        // negation of non-literals and non-numeric literals is kept
+        let expr = negative(col("a"));
+        assert_eq!(
+            expr.clone().fold_negative_literals()?,
+            Transformed::no(expr)
+        );
+        let expr = negative(lit("a"));
+        assert_eq!(
+            expr.clone().fold_negative_literals()?,
+            Transformed::no(expr)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_simplify_case_with_constant_predicate() -> Result<()> {
+        // constant true first branch
+        let expr = when(lit(true), col("x"))
+            .when(col("b"), col("y"))
+            .otherwise(col("z"))?;
+        assert_eq!(
+            expr.simplify_case_with_constant_predicate()?,
+            Transformed::yes(col("x"))
+        );
+
+        // constant false first branch
+        let expr = when(lit(false), col("x"))
+            .when(col("b"), col("y"))
+            .otherwise(col("z"))?;
+        let expected = when(col("b"), col("y")).otherwise(col("z"))?;
+        assert_eq!(
+            expr.simplify_case_with_constant_predicate()?,
+            Transformed::yes(expected)
+        );
+
+        // constant true branch after a non-constant one becomes the else branch
+        let expr = when(col("b"), col("y")).when(lit(true), col("x")).end()?;
+        let expected = when(col("b"), col("y")).otherwise(col("x"))?;
+        assert_eq!(
+            expr.simplify_case_with_constant_predicate()?,
+            Transformed::yes(expected)
+        );
+
+        // no constant conditions
+        let expr = when(col("a"), col("x")).otherwise(col("y"))?;
+        assert_eq!(
+            expr.clone().simplify_case_with_constant_predicate()?,
+            Transformed::no(expr)
+        );
+
+        // the type of the null result is not known
+        let expr = when(lit(false), col("x")).end()?;
+        assert_eq!(
+            expr.clone().simplify_case_with_constant_predicate()?,
+            Transformed::no(expr)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_transform_up_pruning() -> Result<()> {
+        let schema = Schema::new(vec![Field::new("a", DataType::Int32, false)]);
+        let subquery = Arc::new(
+            table_scan(Some("t2"), &schema, None)?
+                .project(vec![col("t2.a")])?
+                .build()?,
+        );
+        let fold_plus = |expr: Expr| match expr {
+            Expr::BinaryExpr(BinaryExpr {
+                left,
+                op: Operator::Plus,
+                right,
+            }) => match (left.as_ref(), right.as_ref()) {
+                (Expr::Literal(l), Expr::Literal(r)) => {
+                    Ok(Transformed::yes(Expr::Literal(l.add(r)?)))
+                }
+                _ => Ok(Transformed::no(binary_expr(*left, Operator::Plus, *right))),
+            },
+            _ => Ok(Transformed::no(expr)),
+        };
+        let is_subquery = |expr: &Expr| {
+            matches!(
+                expr,
+                Expr::ScalarSubquery(_) | Expr::InSubquery(_) | Expr::Exists(_)
+            )
+        };
+
+        let expr = (lit(1) + lit(2))
+            .eq(col("a"))
+            .and(in_subquery(lit(3) + lit(4), subquery));
+        let transformed = expr.transform_up_pruning(is_subquery, &fold_plus)?;
+        assert!(transformed.transformed);
+        let Expr::BinaryExpr(BinaryExpr { left, right, .. }) = transformed.data else {
+            unreachable!()
+        };
+        assert_eq!(*left, lit(3).eq(col("a")));
+        let Expr::InSubquery(InSubquery { expr, .. }) = *right else {
+            unreachable!()
+        };
+        assert_eq!(*expr, lit(3) + lit(4));
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_aggregate_filters() -> Result<()> {
+        let sum_with_filter = |filter: Expr| {
+            Expr::AggregateFunction(AggregateFunction::new_udf(
+                sum_udaf(),
+                vec![col("x")],
+                false,
+                Some(Box::new(filter)),
+                None,
+                None,
+            ))
+        };
+
+        sum_with_filter(col("x").gt(lit(0))).validate_aggregate_filters()?;
+        (sum(col("x")) + lit(1)).validate_aggregate_filters()?;
+
+        let expr = sum_with_filter(count(col("y")).gt(lit(0))) + lit(1);
+        assert_eq!(
+            expr.validate_aggregate_filters()
+                .unwrap_err()
+                .strip_backtrace(),
+            "Error during planning: Aggregate function COUNT(y) is not allowed in the FILTER clause of sum(x) FILTER (WHERE COUNT(y) > Int32(0))"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_transform_to_fixpoint() -> Result<()> {
+        // Strips the innermost alias and stops, so each pass removes one layer
+        let strip_alias = |e: Expr| {
+            Ok(match e {
+                Expr::Alias(Alias { expr, .. }) => {
+                    Transformed::new(*expr, true, TreeNodeRecursion::Stop)
+                }
+                _ => Transformed::no(e),
+            })
+        };
+
+        let expr = col("a").alias("x").alias("y").alias("z");
+        let (expr, iterations) = expr.transform_to_fixpoint(&strip_alias, 10)?;
+        assert_eq!(expr, col("a"));
+        // 3 passes stripping an alias and a final one without change
+        assert_eq!(iterations, 4);
+
+        let expr = col("a").alias("x").alias("y").alias("z");
+        let err = expr.transform_to_fixpoint(&strip_alias, 3).unwrap_err();
+        assert!(err
+            .strip_backtrace()
+            .starts_with("Internal error: Fixpoint not reached after 3 iterations"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_contains_volatile() {
+        assert!((col("a") + random() * lit(2)).contains_volatile());
+        assert!(!(col("a") + lit(1)).contains_volatile());
+        assert!(!coalesce(vec![col("a"), lit(1)]).contains_volatile());
+
+        let volatile_agg = Arc::new(create_udaf(
+            "volatile_agg",
+            vec![DataType::Int32],
+            Arc::new(DataType::Int32),
+            Volatility::Volatile,
+            Arc::new(|_| not_impl_err!("volatile_agg stub")),
+            Arc::new(vec![]),
+        ));
+        let expr = Expr::AggregateFunction(AggregateFunction::new_udf(
+            volatile_agg,
+            vec![col("a")],
+            false,
+            None,
+            None,
+            None,
+        ));
+        assert!(expr.contains_volatile());
+        // `is_volatile` only considers scalar functions
+        assert!(!expr.is_volatile().unwrap());
+        assert!(!sum(col("a")).contains_volatile());
+    }
+
+    #[test]
+    fn test_replace_all() -> Result<()> {
+        let target = col("a") + col("b");
+        let expr = (col("a") + col("b")) * (col("a") + col("b"));
+
+        let result = expr.clone().replace_all(&target, &col("a_plus_b"))?;
+        assert!(result.transformed);
+        assert_eq!(result.data, col("a_plus_b") * col("a_plus_b"));
+        let replacements = result
+            .data
+            .collect_pre_order()
+            .into_iter()
+            .filter(|e| **e == col("a_plus_b"))
+            .count();
+        assert_eq!(replacements, 2);
+
+        let result = expr
+            .clone()
+            .replace_all(&(col("b") + col("a")), &col("x"))?;
+        assert!(!result.transformed);
+        assert_eq!(result.data, expr);
+        Ok(())
+    }
+
+    #[test]
+    fn test_map_columns() -> Result<()> {
+        let expr = (col("a") * lit(2)).gt(col("c"));
+        let result = expr.clone().map_columns(|c| {
+            Ok(if c.name == "a" {
+                col("b") + lit(1)
+            } else {
+                Expr::Column(c)
+            })
+        })?;
+        assert!(result.transformed);
+        assert_eq!(result.data, ((col("b") + lit(1)) * lit(2)).gt(col("c")));
+
+        let result = expr.clone().map_columns(|c| Ok(Expr::Column(c)))?;
+        assert!(!result.transformed);
+        assert_eq!(result.data, expr);
+
+        let err = expr
+            .map_columns(|c| plan_err!("Unknown column {}", c.name))
+            .unwrap_err();
+        assert_eq!(
+            err.strip_backtrace(),
+            "Error during planning: Unknown column a"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            (col("b") + col("a")).normalize().data,
+            (col("a") + col("b")).normalize().data
+        );
+        assert_ne!(
+            (col("b") + col("a")).normalize().transformed,
+            (col("a") + col("b")).normalize().transformed
+        );
+        let normalized = (col("b") + col("a")).normalize().data;
+        assert!(!normalized.normalize().transformed);
+
+        // Nested expressions are normalized bottom-up
+        let expr1 = (col("c") * (col("b") + col("a"))).eq(lit(1));
+        let expr2 = lit(1).eq((col("a") + col("b")) * col("c"));
+        assert_eq!(expr1.normalize().data, expr2.normalize().data);
+
+        let expr = col("b").and(col("a")).or(col("d").not_eq(col("c")));
+        let expected = col("c").not_eq(col("d")).or(col("a").and(col("b")));
+        assert_eq!(expr.normalize().data, expected.normalize().data);
+
+        let expr = bitwise_and(col("b"), col("a"));
+        let expected = bitwise_and(col("a"), col("b"));
+        assert_eq!(expr.normalize().data, expected.normalize().data);
+
+        // Non-commutative operators are left untouched
+        let expr = (col("b") - col("a")) / (col("d").lt(col("c")));
+        let normalized = expr.clone().normalize();
+        assert!(!normalized.transformed);
+        assert_eq!(normalized.data, expr);
+    }
 }
@@ -25,7 +25,7 @@ use std::sync::Arc;
 use super::dml::CopyTo;
 use super::DdlStatement;
 use crate::builder::{change_redundant_column, unnest_with_options};
-use crate::expr::{Placeholder, Sort as SortExpr, WindowFunction};
+use crate::expr::{InSubquery, Placeholder, Sort as SortExpr, WindowFunction};
 use crate::expr_rewriter::{
     create_col_from_scalar_expr, normalize_cols, normalize_sorts, NamePreserver,
 };
@@ -33,9 +33,9 @@ use crate::logical_plan::display::{GraphvizVisitor, IndentVisitor};
 use crate::logical_plan::extension::UserDefinedLogicalNode;
 use crate::logical_plan::{DmlStatement, Statement};
 use crate::utils::{
-    enumerate_grouping_sets, exprlist_len, exprlist_to_fields, find_base_plan,
-    find_out_reference_exprs, grouping_set_expr_count, grouping_set_to_exprlist,
-    split_conjunction,
+    conjunction, enumerate_grouping_sets, exprlist_len, exprlist_to_fields,
+    find_base_plan, find_out_reference_exprs, grouping_set_expr_count,
+    grouping_set_to_exprlist, split_conjunction, split_conjunction_owned,
 };
 use crate::{
     build_join_schema, expr_vec_fmt, BinaryExpr, CreateMemoryTable, CreateView, Expr,
@@ -44,7 +44,10 @@ use crate::{
 };
 
 use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
-use datafusion_common::tree_node::{Transformed, TreeNode, TreeNodeRecursion};
+use datafusion_common::alias::AliasGenerator;
+use datafusion_common::tree_node::{
+    Transformed, TreeNode, TreeNodeRecursion, TreeNodeRewriter,
+};
 use datafusion_common::{
     aggregate_functional_dependencies, internal_err, plan_err, Column, Constraints,
     DFSchema, DFSchemaRef, DataFusionError, Dependency, FunctionalDependence,
@@ -1426,6 +1429,120 @@ impl LogicalPlan {
     }
 }
 
+impl LogicalPlan {
+    /// Rewrites uncorrelated `x IN (SELECT y ...)` and `x NOT IN (SELECT y ...)`
+    /// predicates of `Filter` nodes into `LeftSemi` and `LeftAnti` joins.
+    ///
+    /// Only `IN` predicates that are conjuncts of the filter predicate and whose
+    /// subquery is a single column projection are rewritten. `NOT IN`
+    /// predicates are only rewritten when neither side is nullable, as an anti
+    /// join doesn't follow the SQL semantics of `NOT IN` with `NULL`s. Filters
+    /// embedded in subqueries are also rewritten.
+    ///
+    /// For example
+    /// ```text
+    /// Filter: t1.a IN (<subquery>) AND t1.b > 1
+    ///   Subquery:
+    ///     Projection: t2.a
+    ///       TableScan: t2
+    ///   TableScan: t1
+    /// ```
+    /// is rewritten to
+    /// ```text
+    /// Filter: t1.b > 1
+    ///   LeftSemi Join:  Filter: t1.a = __in_sq_1.a
+    ///     TableScan: t1
+    ///     SubqueryAlias: __in_sq_1
+    ///       Projection: t2.a
+    ///         TableScan: t2
+    /// ```
+    pub fn decorrelate_in_subqueries(self) -> Result<Transformed<LogicalPlan>> {
+        fn is_simple_in_subquery(expr: &Expr, schema: &DFSchema) -> bool {
+            let Expr::InSubquery(InSubquery {
+                expr,
+                subquery,
+                negated,
+            }) = expr
+            else {
+                return false;
+            };
+            let LogicalPlan::Projection(Projection { expr: exprs, .. }) =
+                subquery.subquery.as_ref()
+            else {
+                return false;
+            };
+            subquery.outer_ref_columns.is_empty()
+                && exprs.len() == 1
+                && (!negated
+                    || matches!(expr.nullable(schema), Ok(false))
+                        && !subquery.subquery.schema().field(0).is_nullable())
+        }
+
+        struct InSubqueryToJoin {
+            alias_generator: AliasGenerator,
+        }
+
+        impl TreeNodeRewriter for InSubqueryToJoin {
+            type Node = LogicalPlan;
+
+            fn f_up(&mut self, plan: LogicalPlan) -> Result<Transformed<LogicalPlan>> {
+                let LogicalPlan::Filter(filter) = plan else {
+                    return Ok(Transformed::no(plan));
+                };
+                let schema = Arc::clone(filter.input.schema());
+                if filter.having
+                    || !split_conjunction(&filter.predicate)
+                        .into_iter()
+                        .any(|e| is_simple_in_subquery(e, &schema))
+                {
+                    return Ok(Transformed::no(LogicalPlan::Filter(filter)));
+                }
+
+                let Filter {
+                    predicate, input, ..
+                } = filter;
+                let (in_subqueries, other_predicates): (Vec<_>, Vec<_>) =
+                    split_conjunction_owned(predicate)
+                        .into_iter()
+                        .partition(|e| is_simple_in_subquery(e, &schema));
+                let mut builder = LogicalPlanBuilder::from(Arc::unwrap_or_clone(input));
+                for in_subquery in in_subqueries {
+                    let Expr::InSubquery(InSubquery {
+                        expr,
+                        subquery,
+                        negated,
+                    }) = in_subquery
+                    else {
+                        return internal_err!("Expected InSubquery expression");
+                    };
+                    let alias = self.alias_generator.next("__in_sq");
+                    let right =
+                        LogicalPlanBuilder::from(Arc::unwrap_or_clone(subquery.subquery))
+                            .alias(alias)?
+                            .build()?;
+                    let right_column =
+                        Expr::Column(Column::from(right.schema().qualified_field(0)));
+                    let join_type = if negated {
+                        JoinType::LeftAnti
+                    } else {
+                        JoinType::LeftSemi
+                    };
+                    builder =
+                        builder.join_on(right, join_type, [(*expr).eq(right_column)])?;
+                }
+                if let Some(predicate) = conjunction(other_predicates) {
+                    builder = builder.filter(predicate)?;
+                }
+                Ok(Transformed::yes(builder.build()?))
+            }
+        }
+
+        self.rewrite_with_subqueries(&mut InSubqueryToJoin {
+            alias_generator: AliasGenerator::new(),
+        })
+    }
+}
+
 impl LogicalPlan {
     /// Return a `LogicalPlan` with all placeholders (e.g $1 $2,
     /// ...) replaced with corresponding values provided in
@@ -3078,7 +3195,10 @@ mod tests {
     use super::*;
     use crate::builder::LogicalTableSource;
     use crate::logical_plan::table_scan;
-    use crate::{col, exists, in_subquery, lit, placeholder, GroupingSet};
+    use crate::{
+        col, exists, in_subquery, lit, not_in_subquery, out_ref_col, placeholder,
+        GroupingSet,
+    };
 
     use datafusion_common::tree_node::{TransformedResult, TreeNodeVisitor};
     use datafusion_common::{not_impl_err, Constraint, ScalarValue};
@@ -3684,4 +3804,408 @@ digraph {
         let actual = format!("{}", plan.display_indent());
         assert_eq!(expected.to_string(), actual)
     }
+
+    fn in_subquery_test_schema() -> Schema {
+        Schema::new(vec![
+            Field::new("a", DataType::Int32, false),
+            Field::new("b", DataType::Int32, false),
+        ])
+    }
+
+    fn in_subquery_test_subquery() -> Result<Arc<LogicalPlan>> {
+        let plan = table_scan(Some("t2"), &in_subquery_test_schema(), None)?
+            .project(vec![col("t2.a")])?
+            .build()?;
+        Ok(Arc::new(plan))
+    }
+
+    #[test]
+    fn test_decorrelate_in_subqueries() -> Result<()> {
+        let plan = table_scan(Some("t1"), &in_subquery_test_schema(), None)?
+            .filter(
+                in_subquery(col("t1.a"), in_subquery_test_subquery()?)
+                    .and(col("t1.b").gt(lit(1))),
+            )?
+            .build()?;
+
+        let transformed = plan.decorrelate_in_subqueries()?;
+        assert!(transformed.transformed);
+
+        let expected = "Filter: t1.b > Int32(1)\
+                        \n  LeftSemi Join:  Filter: t1.a = __in_sq_1.a\
+                        \n    TableScan: t1\
+                        \n    SubqueryAlias: __in_sq_1\
+                        \n      Projection: t2.a\
+                        \n        TableScan: t2";
+        assert_eq!(expected, format!("{}", transformed.data.display_indent()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decorrelate_not_in_subqueries() -> Result<()> {
+        let plan = table_scan(Some("t1"), &in_subquery_test_schema(), None)?
+            .filter(not_in_subquery(col("t1.a"), in_subquery_test_subquery()?))?
+            .build()?;
+
+        let transformed = plan.decorrelate_in_subqueries()?;
+        assert!(transformed.transformed);
+
+        let expected = "LeftAnti Join:  Filter: t1.a = __in_sq_1.a\
+                        \n  TableScan: t1\
+                        \n  SubqueryAlias: __in_sq_1\
+                        \n    Projection: t2.a\
+                        \n      TableScan: t2";
+        assert_eq!(expected, format!("{}", transformed.data.display_indent()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decorrelate_nullable_not_in_subqueries() -> Result<()> {
+        let nullable_schema = Schema::new(vec![
+            Field::new("a", DataType::Int32, true),
+            Field::new("b", DataType::Int32, false),
+        ]);
+        let nullable_subquery = table_scan(Some("t2"), &nullable_schema, None)?
+            .project(vec![col("t2.a")])?
+            .build()?;
+
+        // nullable subquery column
+        let plan = table_scan(Some("t1"), &in_subquery_test_schema(), None)?
+            .filter(not_in_subquery(col("t1.a"), Arc::new(nullable_subquery)))?
+            .build()?;
+        let transformed = plan.clone().decorrelate_in_subqueries()?;
+        assert!(!transformed.transformed);
+        assert_eq!(plan, transformed.data);
+
+        // nullable outer expression
+        let plan = table_scan(Some("t1"), &nullable_schema, None)?
+            .filter(not_in_subquery(col("t1.a"), in_subquery_test_subquery()?))?
+            .build()?;
+        let transformed = plan.clone().decorrelate_in_subqueries()?;
+        assert!(!transformed.transformed);
+        assert_eq!(plan, transformed.data);
+
+        // `IN` is still rewritten when nullable
+        let plan = table_scan(Some("t1"), &nullable_schema, None)?
+            .filter(in_subquery(col("t1.a"), in_subquery_test_subquery()?))?
+            .build()?;
+        assert!(plan.decorrelate_in_subqueries()?.transformed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decorrelate_correlated_in_subqueries() -> Result<()> {
+        let subquery = table_scan(Some("t2"), &in_subquery_test_schema(), None)?
+            .filter(col("t2.b").eq(out_ref_col(DataType::Int32, "t1.b")))?
+            .project(vec![col("t2.a")])?
+            .build()?;
+        let plan = table_scan(Some("t1"), &in_subquery_test_schema(), None)?
+            .filter(in_subquery(col("t1.a"), Arc::new(subquery)))?
+            .build()?;
+
+        let transformed = plan.clone().decorrelate_in_subqueries()?;
+        assert!(!transformed.transformed);
+        assert_eq!(plan, transformed.data);
+
+        Ok(())
+    }
+
+    fn multiple_in_subqueries_plan() -> Result<LogicalPlan> {
+        let correlated_subquery =
+            table_scan(Some("t3"), &in_subquery_test_schema(), None)?
+                .filter(col("t3.b").eq(out_ref_col(DataType::Int32, "t1.b")))?
+                .project(vec![col("t3.a")])?
+                .build()?;
+        table_scan(Some("t1"), &in_subquery_test_schema(), None)?
+            .filter(
+                in_subquery(col("t1.a"), in_subquery_test_subquery()?)
+                    .and(in_subquery(col("t1.b"), Arc::new(correlated_subquery))),
+            )?
+            .build()
+    }
+
+    #[test]
+    fn test_rewrite_with_subqueries() -> Result<()> {
+        struct RenameTables;
+
+        impl TreeNodeRewriter for RenameTables {
+            type Node = LogicalPlan;
+
+            fn f_up(&mut self, plan: LogicalPlan) -> Result<Transformed<LogicalPlan>> {
+                Ok(match plan {
+                    LogicalPlan::TableScan(mut scan)
+                        if scan.table_name.table() != "t1" =>
+                    {
+                        scan.table_name =
+                            TableReference::bare(format!("{}_renamed", scan.table_name));
+                        Transformed::yes(LogicalPlan::TableScan(scan))
+                    }
+                    _ => Transformed::no(plan),
+                })
+            }
+        }
+
+        let plan = multiple_in_subqueries_plan()?;
+
+        // `rewrite` doesn't descend into subqueries
+        let transformed = plan.clone().rewrite(&mut RenameTables)?;
+        assert!(!transformed.transformed);
+
+        let transformed = plan.rewrite_with_subqueries(&mut RenameTables)?;
+        assert!(transformed.transformed);
+
+        let mut table_names = vec![];
+        transformed.data.apply_with_subqueries(|plan| {
+            if let LogicalPlan::TableScan(scan) = plan {
+                table_names.push(scan.table_name.to_string());
+            }
+            Ok(TreeNodeRecursion::Continue)
+        })?;
+        assert_eq!(table_names, vec!["t2_renamed", "t3_renamed", "t1"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_with_subqueries() -> Result<()> {
+        let plan = multiple_in_subqueries_plan()?;
+
+        let mut table_names = vec![];
+        plan.apply(|plan| {
+            if let LogicalPlan::TableScan(scan) = plan {
+                table_names.push(scan.table_name.to_string());
+            }
+            Ok(TreeNodeRecursion::Continue)
+        })?;
+        assert_eq!(table_names, vec!["t1"]);
+
+        let mut table_names = vec![];
+        plan.apply_with_subqueries(|plan| {
+            if let LogicalPlan::TableScan(scan) = plan {
+                table_names.push(scan.table_name.to_string());
+            }
+            Ok(TreeNodeRecursion::Continue)
+        })?;
+        assert_eq!(table_names, vec!["t2", "t3", "t1"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transform_unchanged_union() -> Result<()> {
+        let union_plan = || -> Result<LogicalPlan> {
+            let schema = Schema::new(vec![Field::new("a", DataType::Int32, false)]);
+            let scan = table_scan(Some("t"), &schema, None)?.build()?;
+            let mut builder = LogicalPlanBuilder::from(scan.clone());
+            for _ in 0..23 {
+                builder = builder.union(scan.clone())?;
+            }
+            builder.build()
+        };
+
+        let mut calls = 0;
+        let transformed = union_plan()?.transform_up(|plan| {
+            calls += 1;
+            Ok(Transformed::no(plan))
+        })?;
+        assert!(!transformed.transformed);
+        assert_eq!(union_plan()?, transformed.data);
+        // 23 unions and 24 table scans, each visited exactly once
+        assert_eq!(calls, 23 + 24);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_map_children_parallel() -> Result<()> {
+        let schema = Schema::new(vec![Field::new("a", DataType::Int32, false)]);
+        let inputs = (0..100)
+            .map(|i| {
+                table_scan(Some(format!("t{i}")), &schema, None)?
+                    .build()
+                    .map(Arc::new)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let schema = Arc::clone(inputs[0].schema());
+        let plan = LogicalPlan::Union(Union { inputs, schema });
+
+        let rename = |plan: LogicalPlan| {
+            plan.transform_up(|plan| match plan {
+                LogicalPlan::TableScan(mut scan) => {
+                    scan.table_name =
+                        TableReference::bare(format!("renamed_{}", scan.table_name));
+                    Ok(Transformed::yes(LogicalPlan::TableScan(scan)))
+                }
+                _ => Ok(Transformed::no(plan)),
+            })
+        };
+
+        let sequential = plan.clone().map_children(rename)?;
+        let parallel = plan.clone().map_children_parallel(rename)?;
+        assert!(parallel.transformed);
+        assert_eq!(parallel, sequential);
+
+        let LogicalPlan::Union(union) = &parallel.data else {
+            unreachable!()
+        };
+        assert_eq!(union.inputs.len(), 100);
+        let LogicalPlan::TableScan(scan) = union.inputs[42].as_ref() else {
+            unreachable!()
+        };
+        assert_eq!(scan.table_name, TableReference::bare("renamed_t42"));
+
+        let unchanged = plan
+            .clone()
+            .map_children_parallel(|plan| Ok(Transformed::no(plan)))?;
+        assert!(!unchanged.transformed);
+        assert_eq!(unchanged.data, plan);
+
+        let stop = plan.map_children_parallel(|plan| {
+            Ok(Transformed::new(plan, false, TreeNodeRecursion::Stop))
+        });
+        assert!(stop.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_expressions() -> Result<()> {
+        let schema = Schema::new(vec![
+            Field::new("a", DataType::Int32, false),
+            Field::new("b", DataType::Int32, false),
+        ]);
+        let plan = table_scan(Some("t"), &schema, None)?
+            .filter(col("a").gt(lit(1)))?
+            .project(vec![col("a"), col("b"), col("a") + col("b")])?
+            .build()?;
+
+        let mut exprs = vec![];
+        plan.apply_expressions(|e| {
+            exprs.push(e.to_string());
+            Ok(TreeNodeRecursion::Continue)
+        })?;
+        assert_eq!(exprs, vec!["t.a", "t.b", "t.a + t.b"]);
+
+        // `Stop` ends the enumeration
+        let mut exprs = vec![];
+        let tnr = plan.apply_expressions(|e| {
+            exprs.push(e.to_string());
+            Ok(TreeNodeRecursion::Stop)
+        })?;
+        assert_eq!(tnr, TreeNodeRecursion::Stop);
+        assert_eq!(exprs, vec!["t.a"]);
+
+        // Expressions of the inputs are not included
+        let mut exprs = vec![];
+        plan.inputs()[0].apply_expressions(|e| {
+            exprs.push(e.to_string());
+            Ok(TreeNodeRecursion::Continue)
+        })?;
+        assert_eq!(exprs, vec!["t.a > Int32(1)"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_map_expressions() -> Result<()> {
+        let schema = Schema::new(vec![
+            Field::new("a", DataType::Int32, false),
+            Field::new("b", DataType::Int32, false),
+        ]);
+        let plan = table_scan(Some("t"), &schema, None)?
+            .filter(col("a").gt(col("b")))?
+            .build()?;
+
+        let rename = |e: Expr| {
+            e.transform_up(|e| match e {
+                Expr::Column(c) => Ok(Transformed::yes(Expr::Column(Column::new(
+                    c.relation,
+                    format!("renamed_{}", c.name),
+                )))),
+                _ => Ok(Transformed::no(e)),
+            })
+        };
+        let transformed = plan.clone().map_expressions(rename)?;
+        assert!(transformed.transformed);
+        let LogicalPlan::Filter(filter) = &transformed.data else {
+            unreachable!()
+        };
+        assert_eq!(filter.predicate.to_string(), "t.renamed_a > t.renamed_b");
+        // The input plan is kept as is
+        let LogicalPlan::Filter(original) = &plan else {
+            unreachable!()
+        };
+        assert!(Arc::ptr_eq(&filter.input, &original.input));
+
+        let unchanged = plan.clone().map_expressions(|e| Ok(Transformed::no(e)))?;
+        assert!(!unchanged.transformed);
+        assert_eq!(unchanged.data, plan);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transform_subqueries_up() -> Result<()> {
+        fn rename_tables(plan: LogicalPlan) -> Result<Transformed<LogicalPlan>> {
+            Ok(match plan {
+                LogicalPlan::TableScan(mut scan) => {
+                    scan.table_name =
+                        TableReference::bare(format!("{}_renamed", scan.table_name));
+                    Transformed::yes(LogicalPlan::TableScan(scan))
+                }
+                _ => Transformed::no(plan),
+            })
+        }
+
+        fn table_names(plan: &LogicalPlan) -> Result<Vec<String>> {
+            let mut table_names = vec![];
+            plan.apply_with_subqueries(|plan| {
+                if let LogicalPlan::TableScan(scan) = plan {
+                    table_names.push(scan.table_name.to_string());
+                }
+                Ok(TreeNodeRecursion::Continue)
+            })?;
+            Ok(table_names)
+        }
+
+        // The input of the node is not rewritten
+        let transformed =
+            multiple_in_subqueries_plan()?.transform_subqueries_up(rename_tables)?;
+        assert!(transformed.transformed);
+        assert_eq!(
+            table_names(&transformed.data)?,
+            vec!["t2_renamed", "t3_renamed", "t1"]
+        );
+
+        // Nested subqueries and a subquery that appears twice
+        let nested = Arc::new(
+            table_scan(Some("t3"), &in_subquery_test_schema(), None)?
+                .filter(in_subquery(col("t3.a"), in_subquery_test_subquery()?))?
+                .project(vec![col("t3.a")])?
+                .build()?,
+        );
+        let plan = table_scan(Some("t1"), &in_subquery_test_schema(), None)?
+            .filter(
+                in_subquery(col("t1.a"), Arc::clone(&nested))
+                    .and(in_subquery(col("t1.b"), nested)),
+            )?
+            .build()?;
+        let transformed = plan.transform_subqueries_up(rename_tables)?;
+        assert!(transformed.transformed);
+        assert_eq!(
+            table_names(&transformed.data)?,
+            vec!["t2_renamed", "t3_renamed", "t2_renamed", "t3_renamed", "t1"]
+        );
+
+        let plan = multiple_in_subqueries_plan()?;
+        let unchanged = plan
+            .clone()
+            .transform_subqueries_up(|plan| Ok(Transformed::no(plan)))?;
+        assert!(!unchanged.transformed);
+        assert_eq!(unchanged.data, plan);
+        Ok(())
+    }
 }
@@ -47,13 +47,15 @@ use std::sync::Arc;
 
 use crate::expr::{Exists, InSubquery};
 use crate::tree_node::{transform_sort_option_vec, transform_sort_vec};
+#[cfg(feature = "parallel")]
+use crate::EmptyRelation;
+#[cfg(feature = "parallel")]
+use datafusion_common::internal_err;
 use datafusion_common::tree_node::{
     Transformed, TreeNode, TreeNodeIterator, TreeNodeRecursion, TreeNodeRewriter,
     TreeNodeVisitor,
 };
-use datafusion_common::{
-    internal_err, map_until_stop_and_collect, DataFusionError, Result,
-};
+use datafusion_common::{map_until_stop_and_collect, DataFusionError, Result};
 
 impl TreeNode for LogicalPlan {
     fn apply_children<'n, F: FnMut(&'n Self) -> Result<TreeNodeRecursion>>(
@@ -765,6 +767,13 @@ impl LogicalPlan {
         self,
         rewriter: &mut R,
     ) -> Result<Transformed<Self>> {
+        if !rewriter.should_visit(&self) {
+            return self
+                .map_subqueries(|c| c.rewrite_with_subqueries(rewriter))?
+                .transform_sibling(|n| {
+                    n.map_children(|c| c.rewrite_with_subqueries(rewriter))
+                });
+        }
         handle_transform_recursion!(
             rewriter.f_down(self),
             |c| c.rewrite_with_subqueries(rewriter),
@@ -884,6 +893,22 @@ impl LogicalPlan {
         transform_down_up_with_subqueries_impl(self, &mut f_down, &mut f_up)
     }
 
+    /// Rewrites the subqueries that may appear in the expressions of this node,
+    /// such as `IN (SELECT ...)`, bottom-up using `f`, including the subqueries
+    /// nested in them.
+    ///
+    /// Unlike [`Self::transform_up_with_subqueries`], neither this node nor its
+    /// inputs are rewritten. Each occurrence of a subquery is rewritten
+    /// independently, even if the same subquery appears multiple times.
+    ///
+    /// Returns the current node.
+    pub fn transform_subqueries_up<F: FnMut(Self) -> Result<Transformed<Self>>>(
+        self,
+        mut f: F,
+    ) -> Result<Transformed<Self>> {
+        self.map_subqueries(|subquery| subquery.transform_up_with_subqueries(&mut f))
+    }
+
     /// Similarly to [`Self::apply`], calls `f` on  this node and its inputs
     /// including subqueries that may appear in expressions such as `IN (SELECT
     /// ...)`.
@@ -914,37 +939,59 @@ impl LogicalPlan {
         self,
         mut f: F,
     ) -> Result<Transformed<Self>> {
-        self.map_expressions(|expr| {
-            expr.transform_down(|expr| match expr {
-                Expr::Exists(Exists { subquery, negated }) => {
-                    f(LogicalPlan::Subquery(subquery))?.map_data(|s| match s {
-                        LogicalPlan::Subquery(subquery) => {
-                            Ok(Expr::Exists(Exists { subquery, negated }))
-                        }
-                        _ => internal_err!("Transformation should return Subquery"),
-                    })
-                }
-                Expr::InSubquery(InSubquery {
-                    expr,
-                    subquery,
-                    negated,
-                }) => f(LogicalPlan::Subquery(subquery))?.map_data(|s| match s {
-                    LogicalPlan::Subquery(subquery) => Ok(Expr::InSubquery(InSubquery {
-                        expr,
-                        subquery,
-                        negated,
-                    })),
-                    _ => internal_err!("Transformation should return Subquery"),
-                }),
-                Expr::ScalarSubquery(subquery) => f(LogicalPlan::Subquery(subquery))?
-                    .map_data(|s| match s {
-                        LogicalPlan::Subquery(subquery) => {
-                            Ok(Expr::ScalarSubquery(subquery))
-                        }
-                        _ => internal_err!("Transformation should return Subquery"),
-                    }),
-                _ => Ok(Transformed::no(expr)),
-            })
+        self.map_expressions(|expr| expr.map_subqueries(&mut f))
+    }
+
+    /// Same as [`Self::map_children`] but transforms the children concurrently
+    /// using [`rayon`].
+    ///
+    /// This can help with very wide plans (e.g. unions of hundreds of inputs)
+    /// and expensive rewrites. The order of the children is preserved and the
+    /// `transformed` flags of the children are aggregated as usual.
+    ///
+    /// As the children are transformed independently, the sequential
+    /// [`TreeNodeRecursion::Jump`] and [`TreeNodeRecursion::Stop`] semantics
+    /// can't be preserved, so `f` must always return
+    /// [`TreeNodeRecursion::Continue`], otherwise an internal error is returned.
+    #[cfg(feature = "parallel")]
+    pub fn map_children_parallel<F>(self, f: F) -> Result<Transformed<Self>>
+    where
+        F: Fn(Self) -> Result<Transformed<Self>> + Send + Sync,
+    {
+        use rayon::prelude::*;
+
+        // Move the children out of the plan, the placeholders are replaced with
+        // the transformed children afterwards
+        let mut children = vec![];
+        let plan = self
+            .map_children(|c| {
+                let placeholder = LogicalPlan::EmptyRelation(EmptyRelation {
+                    produce_one_row: false,
+                    schema: Arc::clone(c.schema()),
+                });
+                children.push(c);
+                Ok(Transformed::no(placeholder))
+            })?
+            .data;
+
+        let new_children = children
+            .into_par_iter()
+            .map(f)
+            .collect::<Result<Vec<_>>>()?;
+        if new_children
+            .iter()
+            .any(|c| c.tnr != TreeNodeRecursion::Continue)
+        {
+            return internal_err!(
+                "map_children_parallel requires f to return TreeNodeRecursion::Continue"
+            );
+        }
+
+        let mut new_children = new_children.into_iter();
+        plan.map_children(|_| {
+            Ok(new_children
+                .next()
+                .expect("plan has the same number of children"))
         })
     }
 }
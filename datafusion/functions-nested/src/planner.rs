@@ -186,3 +186,62 @@ impl ExprPlanner for FieldAccessPlanner {
 fn is_array_agg(agg_func: &datafusion_expr::expr::AggregateFunction) -> bool {
     return agg_func.func.name() == "array_agg";
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use datafusion_expr::{col, lit};
+
+    fn plan_field_access(field_access: GetFieldAccess) -> Result<Expr> {
+        let expr = RawFieldAccessExpr {
+            expr: col("a"),
+            field_access,
+        };
+        match FieldAccessPlanner.plan_field_access(expr, &DFSchema::empty())? {
+            PlannerResult::Planned(expr) => Ok(expr),
+            PlannerResult::Original(expr) => {
+                plan_err!("Field access was not planned: {expr:?}")
+            }
+        }
+    }
+
+    fn assert_function_call(expr: Expr, name: &str, expected_args: Vec<Expr>) {
+        let Expr::ScalarFunction(ScalarFunction { func, args }) = expr else {
+            panic!("Expected a scalar function call, got {expr}");
+        };
+        assert_eq!(func.name(), name);
+        assert_eq!(args, expected_args);
+    }
+
+    #[test]
+    fn test_plan_named_struct_field() -> Result<()> {
+        let expr =
+            plan_field_access(GetFieldAccess::NamedStructField { name: "b".into() })?;
+        assert_function_call(expr, "get_field", vec![col("a"), lit("b")]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_list_index() -> Result<()> {
+        let expr = plan_field_access(GetFieldAccess::ListIndex {
+            key: Box::new(lit(1)),
+        })?;
+        assert_function_call(expr, "array_element", vec![col("a"), lit(1)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_list_range() -> Result<()> {
+        let expr = plan_field_access(GetFieldAccess::ListRange {
+            start: Box::new(lit(1)),
+            stop: Box::new(lit(3)),
+            stride: Box::new(lit(2)),
+        })?;
+        assert_function_call(expr, "array_slice", vec![col("a"), lit(1), lit(3), lit(2)]);
+
+        Ok(())
+    }
+}